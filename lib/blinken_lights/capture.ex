@@ -18,7 +18,7 @@ defmodule BlinkenLights.Capture do
   end
 
   def handle_info({port, {:data, data}}, {port, config}) do
-    IO.puts([":: ", data])
+    data |> decode(format()) |> handle_message()
     {:noreply, {port, config}}
   end
 
@@ -47,18 +47,49 @@ defmodule BlinkenLights.Capture do
     {:ok, json} = DisplayConfig.encode_rust(config)
 
     Port.open({:spawn_executable, exe_path()}, [
-      :stream,
+      framing(format()),
       :use_stdio,
       :binary,
       :exit_status,
-      env: [{~c"DISPLAY_CONFIG", to_charlist(json)}]
+      env: [
+        {~c"DISPLAY_CONFIG", to_charlist(json)},
+        {~c"CONTROL_FORMAT", to_charlist(format())}
+      ]
     ])
   end
 
+  # :json sends lines of JSON, :etf sends terms in {:packet, 4} frames
+  defp format, do: Application.get_env(:blinken_lights, :port_format, :json)
+
+  defp framing(:etf), do: {:packet, 4}
+  defp framing(:json), do: {:line, 65_536}
+
+  defp decode(data, :etf), do: :erlang.binary_to_term(data, [:safe])
+
+  defp decode({:eol, line}, :json) do
+    case Jason.decode(line) do
+      {:ok, message} -> message
+      {:error, _} -> line
+    end
+  end
+
+  # the rest of a line too long for one message
+  defp decode({:noeol, chunk}, :json), do: chunk
+
+  # beats drive the colour cycle, everything else is only logged
+  defp handle_message(%{"beat" => _beat}), do: BlinkenLights.ColourCycle.beat()
+  defp handle_message(message), do: IO.inspect(message, label: "::")
+
   defp exe_path, do: Path.expand("../../target/release/leds", __DIR__) |> to_charlist()
 
   defp send_config({port, config}) do
+    true = Port.command(port, encode(format(), config))
+  end
+
+  defp encode(:etf, config), do: config |> DisplayConfig.to_rust() |> :erlang.term_to_binary()
+
+  defp encode(:json, config) do
     {:ok, json} = DisplayConfig.encode_rust(config)
-    true = Port.command(port, IO.iodata_to_binary([json, "\n"]))
+    IO.iodata_to_binary([json, "\n"])
   end
 end
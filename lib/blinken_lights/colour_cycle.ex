@@ -1,6 +1,10 @@
 defmodule BlinkenLights.ColourCycle do
   use GenServer, restart: :transient
 
+  # how long after the last beat the timer takes over again, a little longer
+  # than a beat at the slowest tempo the lights track (60 bpm)
+  @beat_timeout 2_000
+
   def start_link(config) do
     GenServer.start_link(__MODULE__, config, name: __MODULE__)
   end
@@ -29,6 +33,18 @@ defmodule BlinkenLights.ColourCycle do
     end
   end
 
+  # Move the hues on by a step for a beat reported by the lights. While beats
+  # are coming in they drive the cycle and the timer holds off.
+  def beat do
+    case GenServer.whereis(__MODULE__) do
+      nil ->
+        :ok
+
+      pid when is_pid(pid) ->
+        GenServer.cast(pid, :beat)
+    end
+  end
+
   def running? do
     __MODULE__
     |> GenServer.whereis()
@@ -37,7 +53,7 @@ defmodule BlinkenLights.ColourCycle do
 
   def init(config) do
     %{white: %{hue: white}, black: %{hue: black}, colour_cycle_speed: speed} = config
-    {:ok, %{white: white, black: black, speed: speed}, {:continue, :start}}
+    {:ok, %{white: white, black: black, speed: speed, last_beat: nil}, {:continue, :start}}
   end
 
   def handle_continue(:start, state) do
@@ -56,16 +72,17 @@ defmodule BlinkenLights.ColourCycle do
     {:reply, :ok, state, {:continue, :stop_cycle}}
   end
 
+  def handle_cast(:beat, %{speed: speed} = state) do
+    state = advance(state, beat_step(speed))
+    {:noreply, %{state | last_beat: System.monotonic_time(:millisecond)}}
+  end
+
   def handle_info(:cycle, state) do
     {:noreply, cycle(state)}
   end
 
-  defp cycle(state) do
-    state =
-      %{white: white, black: black, speed: speed} =
-      state |> next(:white) |> next(:black)
-
-    BlinkenLights.config(white: %{hue: white}, black: %{hue: black})
+  defp cycle(%{speed: speed} = state) do
+    state = if beat_locked?(state), do: state, else: advance(state, 1)
 
     ## if you change this interval calculation, change the duration calculation
     # in app.tsx
@@ -74,9 +91,27 @@ defmodule BlinkenLights.ColourCycle do
     state
   end
 
-  defp next(state, colour) do
+  defp advance(state, degrees) do
+    state =
+      %{white: white, black: black} =
+      state |> next(:white, degrees) |> next(:black, degrees)
+
+    BlinkenLights.config(white: %{hue: white}, black: %{hue: black})
+    state
+  end
+
+  # faster cycles take bigger steps on each beat, 1 to 30 degrees
+  defp beat_step(speed), do: 1 + speed * 29
+
+  defp beat_locked?(%{last_beat: nil}), do: false
+
+  defp beat_locked?(%{last_beat: last_beat}) do
+    System.monotonic_time(:millisecond) - last_beat < @beat_timeout
+  end
+
+  defp next(state, colour, degrees) do
     hue = Map.fetch!(state, colour)
-    hue = :math.fmod(hue + 1, 360.0)
+    hue = :math.fmod(hue + degrees, 360.0)
     Map.put(state, colour, hue)
   end
 end
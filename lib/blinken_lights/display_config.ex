@@ -47,6 +47,16 @@ defmodule BlinkenLights.DisplayConfig do
     |> Jason.encode()
   end
 
+  # the same keys as a plain map, for sending as an Erlang term
+  def to_rust(%__MODULE__{} = config) do
+    config
+    |> Map.take(@rust_keys)
+    |> Map.new(fn
+      {k, %Colour{} = colour} -> {k, Map.from_struct(colour)}
+      pair -> pair
+    end)
+  end
+
   def start_link(config) do
     GenServer.start_link(__MODULE__, config, name: __MODULE__)
   end
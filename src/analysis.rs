@@ -0,0 +1,101 @@
+use spectrum_analyzer::scaling::{
+    SpectrumDataStats,
+    combined,
+    // divide_by_N,
+    divide_by_N_sqrt,
+    scale_to_zero_to_one,
+};
+use spectrum_analyzer::windows::hann_window;
+use spectrum_analyzer::{FrequencyLimit, samples_fft_to_spectrum};
+
+use crate::display::{DisplayConfig, Mode};
+use crate::{piano, tuner};
+
+/// Number of samples in each FFT.
+pub const SAMPLE_SIZE: usize = 2usize.pow(13);
+
+/// Input with an RMS below this (about -40 dBFS) is taken as silence. The
+/// levels from `analyse` are scaled so the loudest key is always near 1,
+/// with or without anything playing, so they can't tell on their own.
+pub const QUIET_RMS: f32 = 0.01;
+
+/// What is known about the input behind a frame's levels, beyond the levels
+/// themselves.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Input {
+    /// audio, with the RMS of the frame's samples
+    Audio {
+        /// root mean square of the samples, 1 at full scale
+        rms: f32,
+    },
+    /// key velocities from MIDI, 0 for keys that aren't held
+    Midi,
+    /// levels recorded earlier, with nothing to say how loud they were
+    #[default]
+    Replay,
+}
+
+impl Input {
+    /// is the input silent, whatever the levels look like
+    pub fn quiet(self) -> bool {
+        matches!(self, Input::Audio { rms } if rms < QUIET_RMS)
+    }
+}
+
+/// The root mean square of `samples`, 0 for none.
+pub fn rms(samples: &[f32]) -> f32 {
+    if samples.is_empty() {
+        return 0.0;
+    }
+    let squares: f32 = samples.iter().map(|s| s * s).sum();
+    (squares / samples.len() as f32).sqrt()
+}
+
+// 20·log10 of the magnitude, floored at 0 dB. spectrum-analyzer's
+// `scale_20_times_log10` goes negative below a magnitude of 1, which
+// `scale_to_zero_to_one` asserts against in debug builds and turns into
+// negative levels in release ones.
+fn decibels(value: f32, _stats: &SpectrumDataStats) -> f32 {
+    if value > 1.0 {
+        20.0 * value.log10()
+    } else {
+        0.0
+    }
+}
+
+/// Fill `bins` with the per-key levels in the FFT of `samples`, weighted and
+/// equalised as `config` says. Returns the dominant pitch when the tuner
+/// needs it.
+pub fn analyse(
+    samples: &[f32],
+    sample_rate: u32,
+    bins: &mut [f32],
+    config: &DisplayConfig,
+) -> Option<tuner::Pitch> {
+    let hann_window = hann_window(samples);
+    let fncs: Box<spectrum_analyzer::scaling::SpectrumScalingFunction> = if config.scale {
+        Box::new(&divide_by_N_sqrt)
+    } else {
+        combined(&[
+            &decibels,
+            // &divide_by_N_sqrt,
+            &scale_to_zero_to_one,
+        ])
+    };
+    let spectrum = samples_fft_to_spectrum(
+        &hann_window,
+        sample_rate,
+        FrequencyLimit::Range(piano::MIN_FREQUENCY, piano::MAX_FREQUENCY),
+        Some(&fncs),
+    )
+    .unwrap();
+
+    let num_bins = bins.len();
+    piano::bin_magnitudes(bins, &spectrum, num_bins, config);
+    config.equalisation.apply(bins);
+    if config.mode == Mode::Tuner {
+        tuner::detect(&spectrum)
+    } else {
+        None
+    }
+}
@@ -0,0 +1,166 @@
+use std::error::Error;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, mpsc};
+use std::thread;
+use std::time::Duration;
+
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use ringbuf::traits::*;
+
+use crate::analysis::SAMPLE_SIZE;
+
+const RINGBUFFER_SIZE: usize = SAMPLE_SIZE;
+// a gap between callbacks this much longer than the audio they carried
+// means samples were dropped
+const OVERRUN_SLACK: f64 = 1.5;
+
+#[derive(Clone, Copy)]
+enum Ping {
+    Audio,
+    Timeout,
+}
+
+/// The most recent samples from the default input device.
+pub struct AudioInput {
+    buffer: Arc<Mutex<ringbuf::HeapRb<f32>>>,
+    sample_rate: u32,
+    overruns: Arc<AtomicU64>,
+    // why the input stopped, set by the watchdog or the stream
+    failure: Arc<Mutex<Option<String>>>,
+}
+
+impl AudioInput {
+    /// Copy the latest samples into `samples`, oldest first. Fails once the
+    /// audio has stopped arriving or the device has reported an error.
+    pub fn read(&self, samples: &mut [f32]) -> Result<(), String> {
+        if let Some(failure) = failed(&self.failure) {
+            return Err(failure);
+        }
+        if let Ok(buffer) = self.buffer.lock() {
+            let _samples_read = buffer.peek_slice(samples);
+        }
+        Ok(())
+    }
+
+    /// samples per second, as the device delivers them
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /// How many times the device has dropped samples since the start.
+    pub fn overruns(&self) -> u64 {
+        self.overruns.load(Ordering::Relaxed)
+    }
+}
+
+fn failed(failure: &Mutex<Option<String>>) -> Option<String> {
+    failure.lock().ok().and_then(|failure| failure.clone())
+}
+
+// only the first failure is kept, later ones tend to follow from it
+fn fail(failure: &Mutex<Option<String>>, message: String) {
+    if let Ok(mut failure) = failure.lock() {
+        failure.get_or_insert(message);
+    }
+}
+
+/// Start capturing from the default input device, with a watchdog that
+/// notices if the audio stops arriving, after which `AudioInput::read`
+/// fails. The stream stops when dropped.
+pub fn start() -> Result<(cpal::Stream, AudioInput), Box<dyn Error>> {
+    let (tx, rx) = mpsc::channel();
+    let ringbuf = ringbuf::HeapRb::<f32>::new(RINGBUFFER_SIZE);
+
+    let shared_buffer = Arc::new(Mutex::new(ringbuf));
+
+    let producer_buffer = Arc::clone(&shared_buffer);
+    let consumer_buffer = Arc::clone(&shared_buffer);
+
+    let host = cpal::default_host();
+    let device = host
+        .default_input_device()
+        .ok_or("no input device available")?;
+
+    let config = device
+        .default_input_config()
+        .map_err(|err| format!("no default input config: {}", err))?;
+
+    let mut stream_config: cpal::StreamConfig = config.into();
+
+    stream_config.buffer_size = cpal::BufferSize::Fixed(1024);
+
+    let tx_audio = tx.clone();
+    let overruns = Arc::new(AtomicU64::new(0));
+    let overruns_counter = Arc::clone(&overruns);
+    let failure = Arc::new(Mutex::new(None));
+    let stream_failure = Arc::clone(&failure);
+    let watchdog_failure = Arc::clone(&failure);
+    let channels = usize::from(stream_config.channels.max(1));
+    let sample_rate = f64::from(stream_config.sample_rate.0);
+    let mut last_capture: Option<(cpal::StreamInstant, f64)> = None;
+
+    let stream = device.build_input_stream(
+        &stream_config,
+        move |samples: &[f32], info: &cpal::InputCallbackInfo| {
+            let capture = info.timestamp().capture;
+            if let Some((previous, seconds)) = last_capture
+                && let Some(gap) = capture.duration_since(&previous)
+                && gap.as_secs_f64() > seconds * OVERRUN_SLACK
+            {
+                overruns_counter.fetch_add(1, Ordering::Relaxed);
+            }
+            last_capture = Some((capture, (samples.len() / channels) as f64 / sample_rate));
+
+            if let Ok(mut buffer) = producer_buffer.lock() {
+                buffer.push_iter_overwrite(&mut samples.iter().copied());
+                // the watchdog has already given up if this fails
+                let _ = tx_audio.send(Ping::Audio);
+            }
+        },
+        move |err| {
+            fail(
+                &stream_failure,
+                format!("an error occurred on stream: {}", err),
+            )
+        },
+        None,
+    )?;
+
+    stream.play()?;
+
+    thread::spawn(move || {
+        let mut last_ping: Option<Ping> = None;
+        while let Ok(ping) = rx.recv() {
+            let message = match (ping, &last_ping) {
+                (Ping::Audio, _) | (Ping::Timeout, Some(Ping::Audio)) => {
+                    last_ping = Some(ping);
+                    continue;
+                }
+                (Ping::Timeout, Some(Ping::Timeout)) => "no audio for two timeouts",
+                (Ping::Timeout, None) => "timed out before any audio arrived",
+            };
+            fail(&watchdog_failure, message.to_string());
+            return;
+        }
+    });
+
+    // stops once the watchdog has hung up
+    thread::spawn(move || {
+        loop {
+            thread::sleep(Duration::from_millis(500));
+            if tx.send(Ping::Timeout).is_err() {
+                return;
+            }
+        }
+    });
+
+    Ok((
+        stream,
+        AudioInput {
+            buffer: consumer_buffer,
+            sample_rate: stream_config.sample_rate.0,
+            overruns,
+            failure,
+        },
+    ))
+}
@@ -0,0 +1,261 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::report;
+
+// the onset envelope is sampled at a fixed rate, independent of how often
+// frames arrive
+const HOP: Duration = Duration::from_millis(10);
+// hops of envelope kept for tempo estimation
+const HISTORY: usize = 600;
+// how often the tempo and phase are re-estimated, in hops
+const ESTIMATE_EVERY: usize = 100;
+const MIN_BPM: f32 = 60.0;
+const MAX_BPM: f32 = 180.0;
+// tempo the estimate leans towards, so we don't lock onto half or double
+// time
+const PREFERRED_BPM: f32 = 120.0;
+// autocorrelation at the best lag must be at least this fraction of the
+// envelope's energy to count as a tempo
+const MIN_PERIODICITY: f32 = 0.1;
+
+/// what happens to the display on each beat
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default, JsonSchema)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum BeatEffect {
+    /// the beat isn't shown
+    #[default]
+    Off,
+    /// brightness jumps by `amount` on the beat and falls back before the next
+    Pulse {
+        /// how much brighter the strip is on the beat, 1 doubles it
+        #[schemars(range(min = 0.0, max = 2.0), extend("x-clamp" = "clamp"))]
+        amount: f32,
+    },
+    /// hues advance by `degrees` on every beat
+    ColourStep {
+        /// degrees round the colour wheel
+        #[schemars(range(min = -360.0, max = 360.0))]
+        degrees: f32,
+    },
+}
+
+/// Where the music is in the current beat.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Beat {
+    /// the estimated tempo in beats per minute
+    pub bpm: f32,
+    /// how far we are through the current beat, 0..1
+    pub phase: f32,
+    /// beats since the tracker started
+    pub count: u64,
+}
+
+#[derive(Serialize)]
+struct Report {
+    beat: BeatReport,
+}
+
+#[derive(Serialize)]
+struct BeatReport {
+    bpm: f32,
+    count: u64,
+}
+
+/// Estimates tempo and beat phase from the spectral flux of the per-key
+/// levels, reporting each beat on stdout.
+pub struct BeatTracker {
+    previous: Vec<f32>,
+    envelope: VecDeque<f32>,
+    // onset strength of the hop currently being filled
+    pending: f32,
+    since_hop: Duration,
+    hops: u64,
+    since_estimate: usize,
+    // beat period in hops
+    period: Option<usize>,
+    last_beat: u64,
+    count: u64,
+}
+
+impl BeatTracker {
+    /// A tracker with no tempo yet, it takes a few seconds of music to
+    /// find one.
+    pub fn new() -> Self {
+        BeatTracker {
+            previous: Vec::new(),
+            envelope: VecDeque::with_capacity(HISTORY),
+            pending: 0.0,
+            since_hop: Duration::ZERO,
+            hops: 0,
+            since_estimate: 0,
+            period: None,
+            last_beat: 0,
+            count: 0,
+        }
+    }
+
+    /// Take the levels of the next frame, `elapsed` after the last one.
+    /// Returns the current beat once a tempo has been found.
+    pub fn update(&mut self, bins: &[f32], elapsed: Duration) -> Option<Beat> {
+        let flux: f32 = if self.previous.len() == bins.len() {
+            bins.iter()
+                .zip(&self.previous)
+                .map(|(now, before)| (now - before).max(0.0))
+                .sum()
+        } else {
+            0.0
+        };
+        self.previous.clear();
+        self.previous.extend_from_slice(bins);
+        self.pending = self.pending.max(flux);
+
+        self.since_hop += elapsed;
+        while self.since_hop >= HOP {
+            self.since_hop -= HOP;
+            self.push_hop();
+        }
+
+        self.period.map(|period| Beat {
+            bpm: bpm(period),
+            phase: ((self.hops - self.last_beat) as f32 / period as f32).min(1.0),
+            count: self.count,
+        })
+    }
+
+    fn push_hop(&mut self) {
+        if self.envelope.len() == HISTORY {
+            self.envelope.pop_front();
+        }
+        self.envelope.push_back(self.pending);
+        self.pending = 0.0;
+        self.hops += 1;
+
+        self.since_estimate += 1;
+        if self.since_estimate >= ESTIMATE_EVERY {
+            self.since_estimate = 0;
+            self.estimate();
+        }
+
+        if let Some(period) = self.period
+            && self.hops >= self.last_beat + period as u64
+        {
+            self.last_beat += period as u64;
+            self.count += 1;
+            report::send(&Report {
+                beat: BeatReport {
+                    bpm: bpm(period),
+                    count: self.count,
+                },
+            });
+        }
+    }
+
+    fn estimate(&mut self) {
+        let envelope: Vec<f32> = self.envelope.iter().copied().collect();
+        self.period = tempo_period(&envelope);
+        if let Some(period) = self.period {
+            let offset = beat_offset(&envelope, period);
+            self.last_beat = self.hops - offset as u64;
+        }
+    }
+}
+
+impl Default for BeatTracker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn bpm(period: usize) -> f32 {
+    60.0 / (period as f32 * HOP.as_secs_f32())
+}
+
+// The beat period, in hops, with the strongest autocorrelation in the onset
+// envelope.
+fn tempo_period(envelope: &[f32]) -> Option<usize> {
+    let min_lag = (60.0 / (MAX_BPM * HOP.as_secs_f32())).round() as usize;
+    let max_lag = (60.0 / (MIN_BPM * HOP.as_secs_f32())).round() as usize;
+    if envelope.len() < max_lag * 2 {
+        return None;
+    }
+    let mean = envelope.iter().sum::<f32>() / envelope.len() as f32;
+    let centred: Vec<f32> = envelope.iter().map(|v| v - mean).collect();
+    let energy: f32 = centred.iter().map(|v| v * v).sum();
+    if energy <= f32::EPSILON {
+        return None;
+    }
+
+    let mut best: Option<(usize, f32)> = None;
+    for lag in min_lag..=max_lag {
+        let acf: f32 = centred[lag..]
+            .iter()
+            .zip(&centred)
+            .map(|(a, b)| a * b)
+            .sum();
+        let weight = (-0.5 * ((bpm(lag) / PREFERRED_BPM).log2() / 0.9).powi(2)).exp();
+        let score = acf * weight;
+        if best.is_none_or(|(_, best_score)| score > best_score) {
+            best = Some((lag, score));
+        }
+    }
+    best.filter(|(_, score)| score / energy >= MIN_PERIODICITY)
+        .map(|(lag, _)| lag)
+}
+
+// How many hops ago the most recent beat fell, found by lining up a comb of
+// `period` spacing with the onset envelope.
+fn beat_offset(envelope: &[f32], period: usize) -> usize {
+    let last = envelope.len() - 1;
+    (0..period)
+        .map(|offset| {
+            let strength: f32 = (offset..=last)
+                .step_by(period)
+                .map(|ago| envelope[last - ago])
+                .sum();
+            (offset, strength)
+        })
+        .fold((0, f32::MIN), |best, candidate| {
+            if candidate.1 > best.1 {
+                candidate
+            } else {
+                best
+            }
+        })
+        .0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tracks_tempo() {
+        let mut tracker = BeatTracker::new();
+        let loud = vec![1.0; 10];
+        let quiet = vec![0.0; 10];
+        let mut beat = None;
+        // 120 BPM is a beat every 50 hops
+        for hop in 0..HISTORY * 2 {
+            let bins = if hop % 50 == 0 { &loud } else { &quiet };
+            beat = tracker.update(bins, HOP);
+        }
+        let beat = beat.unwrap();
+        assert!((beat.bpm - 120.0).abs() < 1.0, "bpm {}", beat.bpm);
+        assert!(beat.count > 0);
+        // the last pulse was 49 hops ago, so we're nearly at the next beat
+        assert!(beat.phase > 0.9, "phase {}", beat.phase);
+    }
+
+    #[test]
+    fn test_no_tempo_in_silence() {
+        let mut tracker = BeatTracker::new();
+        let quiet = vec![0.0; 10];
+        for _ in 0..HISTORY {
+            assert_eq!(tracker.update(&quiet, HOP), None);
+        }
+    }
+}
@@ -0,0 +1,288 @@
+use std::fmt;
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::chroma::Chroma;
+use crate::piano::NOTE_NAMES;
+use crate::report;
+
+// total chroma energy below which we don't try to name a chord, for levels
+// that aren't normalised such as MIDI. Audio levels are, so quiet audio has
+// to be passed in to `ChordTracker::update`.
+const MIN_LEVEL: f32 = 0.2;
+// how closely the chroma has to match a chord template, 0..1
+const MIN_MATCH: f32 = 0.75;
+// frames a new chord has to be held before it's reported
+const HOLD_FRAMES: usize = 10;
+
+/// the kind of chord, whatever its root
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Quality {
+    /// root, major third and fifth
+    Major,
+    /// root, minor third and fifth
+    Minor,
+    /// root, minor third and diminished fifth
+    Diminished,
+    /// root, major third and augmented fifth
+    Augmented,
+    /// root, second and fifth
+    Sus2,
+    /// root, fourth and fifth
+    Sus4,
+    /// a major chord with a minor seventh
+    Dominant7,
+    /// a major chord with a major seventh
+    Major7,
+    /// a minor chord with a minor seventh
+    Minor7,
+    /// a diminished chord with a minor seventh
+    HalfDiminished7,
+}
+
+// in order of preference when two templates match equally well
+const QUALITIES: [Quality; 10] = [
+    Quality::Major,
+    Quality::Minor,
+    Quality::Dominant7,
+    Quality::Minor7,
+    Quality::Major7,
+    Quality::Diminished,
+    Quality::HalfDiminished7,
+    Quality::Augmented,
+    Quality::Sus4,
+    Quality::Sus2,
+];
+
+impl Quality {
+    /// semitones above the root
+    pub fn intervals(&self) -> &'static [usize] {
+        match self {
+            Quality::Major => &[0, 4, 7],
+            Quality::Minor => &[0, 3, 7],
+            Quality::Diminished => &[0, 3, 6],
+            Quality::Augmented => &[0, 4, 8],
+            Quality::Sus2 => &[0, 2, 7],
+            Quality::Sus4 => &[0, 5, 7],
+            Quality::Dominant7 => &[0, 4, 7, 10],
+            Quality::Major7 => &[0, 4, 7, 11],
+            Quality::Minor7 => &[0, 3, 7, 10],
+            Quality::HalfDiminished7 => &[0, 3, 6, 10],
+        }
+    }
+
+    fn suffix(&self) -> &'static str {
+        match self {
+            Quality::Major => "",
+            Quality::Minor => "m",
+            Quality::Diminished => "dim",
+            Quality::Augmented => "aug",
+            Quality::Sus2 => "sus2",
+            Quality::Sus4 => "sus4",
+            Quality::Dominant7 => "7",
+            Quality::Major7 => "maj7",
+            Quality::Minor7 => "m7",
+            Quality::HalfDiminished7 => "m7b5",
+        }
+    }
+
+    // hue used when tinting by quality: bright chords warm, dark chords cool
+    fn hue(&self) -> f32 {
+        match self {
+            Quality::Major => 45.0,
+            Quality::Dominant7 => 20.0,
+            Quality::Major7 => 70.0,
+            Quality::Minor => 220.0,
+            Quality::Minor7 => 250.0,
+            Quality::Diminished => 290.0,
+            Quality::HalfDiminished7 => 310.0,
+            Quality::Augmented => 0.0,
+            Quality::Sus2 => 140.0,
+            Quality::Sus4 => 170.0,
+        }
+    }
+}
+
+/// A chord picked out of the chroma, e.g. A minor.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Chord {
+    /// pitch class, see `piano::key_number_to_index`
+    pub root: usize,
+    /// what kind of chord is built on the root
+    pub quality: Quality,
+}
+
+impl fmt::Display for Chord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}{}", NOTE_NAMES[self.root], self.quality.suffix())
+    }
+}
+
+/// how the strip is tinted by the current chord
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ChordColour {
+    /// the strip keeps its own colours
+    #[default]
+    Off,
+    /// a hue for each kind of chord, e.g. major or minor
+    Quality,
+    /// a hue for each root note, round the colour wheel
+    Root,
+}
+
+impl Chord {
+    /// the hue to tint the strip for this chord, `None` if it isn't tinted
+    pub fn hue(&self, colour: ChordColour) -> Option<f32> {
+        match colour {
+            ChordColour::Off => None,
+            ChordColour::Quality => Some(self.quality.hue()),
+            ChordColour::Root => Some(self.root as f32 * 30.0),
+        }
+    }
+}
+
+/// Find the chord template that best matches the chroma, scored by cosine
+/// similarity.
+pub fn recognise(chroma: &Chroma) -> Option<Chord> {
+    let total: f32 = chroma.iter().sum();
+    if total < MIN_LEVEL {
+        return None;
+    }
+    let norm = chroma.iter().map(|v| v * v).sum::<f32>().sqrt();
+
+    let mut best: Option<(Chord, f32)> = None;
+    for quality in QUALITIES {
+        let intervals = quality.intervals();
+        for root in 0..12 {
+            let dot: f32 = intervals.iter().map(|i| chroma[(root + i) % 12]).sum();
+            let score = dot / (norm * (intervals.len() as f32).sqrt());
+            if best.is_none_or(|(_, best_score)| score > best_score + f32::EPSILON) {
+                best = Some((Chord { root, quality }, score));
+            }
+        }
+    }
+    best.filter(|(_, score)| *score >= MIN_MATCH)
+        .map(|(chord, _)| chord)
+}
+
+#[derive(Serialize)]
+struct Report {
+    chord: Option<ChordReport>,
+}
+
+#[derive(Serialize)]
+struct ChordReport {
+    name: String,
+    root: &'static str,
+    quality: Quality,
+}
+
+/// Debounces the frame-by-frame recognition and reports changes on stdout.
+pub struct ChordTracker {
+    current: Option<Chord>,
+    candidate: Option<Chord>,
+    held: usize,
+}
+
+impl ChordTracker {
+    /// A tracker that hasn't heard a chord yet.
+    pub fn new() -> Self {
+        ChordTracker {
+            current: None,
+            candidate: None,
+            held: 0,
+        }
+    }
+
+    /// Recognise the chord in the next frame's chroma, or count the frame
+    /// as no chord if the input is `quiet` (see `analysis::Input::quiet`).
+    /// Returns the chord that has been held long enough to count, if any.
+    pub fn update(&mut self, chroma: &Chroma, quiet: bool) -> Option<Chord> {
+        let chord = if quiet { None } else { recognise(chroma) };
+        if chord == self.candidate {
+            self.held += 1;
+        } else {
+            self.candidate = chord;
+            self.held = 1;
+        }
+        if self.held >= HOLD_FRAMES && self.candidate != self.current {
+            self.current = self.candidate;
+            self.report();
+        }
+        self.current
+    }
+
+    fn report(&self) {
+        let message = Report {
+            chord: self.current.map(|chord| ChordReport {
+                name: chord.to_string(),
+                root: NOTE_NAMES[chord.root],
+                quality: chord.quality,
+            }),
+        };
+        report::send(&message);
+    }
+}
+
+impl Default for ChordTracker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chroma_of(pitch_classes: &[usize]) -> Chroma {
+        let mut chroma = [0.0; 12];
+        for &p in pitch_classes {
+            chroma[p] = 1.0;
+        }
+        chroma
+    }
+
+    #[test]
+    fn test_recognise() {
+        // C E G
+        let chord = recognise(&chroma_of(&[3, 7, 10])).unwrap();
+        assert_eq!(chord.to_string(), "C");
+        // A C E G
+        let chord = recognise(&chroma_of(&[0, 3, 7, 10])).unwrap();
+        assert_eq!(chord.to_string(), "Am7");
+        // B D F
+        let chord = recognise(&chroma_of(&[2, 5, 8])).unwrap();
+        assert_eq!(chord.to_string(), "Bdim");
+        assert_eq!(recognise(&[0.0; 12]), None);
+    }
+
+    #[test]
+    fn test_tracker_debounces() {
+        let mut tracker = ChordTracker::new();
+        let c_major = chroma_of(&[3, 7, 10]);
+        for _ in 0..HOLD_FRAMES - 1 {
+            assert_eq!(tracker.update(&c_major, false), None);
+        }
+        assert!(tracker.update(&c_major, false).is_some());
+    }
+
+    #[test]
+    fn test_tracker_ignores_quiet_input() {
+        // room noise normalised up to full scale looks like a loud chord
+        let mut tracker = ChordTracker::new();
+        let c_major = chroma_of(&[3, 7, 10]);
+        for _ in 0..HOLD_FRAMES {
+            assert_eq!(tracker.update(&c_major, true), None);
+        }
+        for _ in 0..HOLD_FRAMES {
+            tracker.update(&c_major, false);
+        }
+        for _ in 0..HOLD_FRAMES - 1 {
+            assert!(tracker.update(&c_major, true).is_some());
+        }
+        assert_eq!(tracker.update(&c_major, true), None);
+    }
+}
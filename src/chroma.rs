@@ -0,0 +1,55 @@
+use crate::piano;
+
+/// one value per pitch class, index 0 is A (see `piano::key_number_to_index`)
+pub type Chroma = [f32; 12];
+
+/// Fold the per-key magnitudes into 12 pitch classes, ignoring octave.
+/// Values are summed so a note played in several octaves is stronger, and
+/// scaled back into 0..1 if they overflow.
+pub fn chroma(bins: &[f32]) -> Chroma {
+    let mut chroma = [0.0; 12];
+    for (i, &magnitude) in bins.iter().enumerate() {
+        chroma[piano::key_number_to_index(piano::bin_key_number(i))] += magnitude;
+    }
+    let max = chroma.iter().copied().fold(0.0f32, f32::max);
+    if max > 1.0 {
+        for val in chroma.iter_mut() {
+            *val /= max;
+        }
+    }
+    chroma
+}
+
+/// Replace every key's magnitude with that of its pitch class, so the chroma
+/// vector is repeated across the strip once per octave.
+pub fn spread(chroma: &Chroma, bins: &mut [f32]) {
+    for (i, val) in bins.iter_mut().enumerate() {
+        *val = chroma[piano::key_number_to_index(piano::bin_key_number(i))];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bin_for_key(key_number: usize) -> usize {
+        key_number - piano::bin_key_number(0)
+    }
+
+    #[test]
+    fn test_chroma_folds_octaves() {
+        let mut bins = vec![0.0; piano::num_keys()];
+        // A3, A4 and C5
+        bins[bin_for_key(37)] = 0.25;
+        bins[bin_for_key(49)] = 0.5;
+        bins[bin_for_key(52)] = 0.5;
+        let chroma = chroma(&bins);
+        assert_eq!(chroma[0], 0.75);
+        assert_eq!(chroma[3], 0.5);
+        assert_eq!(chroma[1], 0.0);
+
+        spread(&chroma, &mut bins);
+        assert_eq!(bins[bin_for_key(61)], 0.75);
+        assert_eq!(bins[bin_for_key(40)], 0.5);
+    }
+}
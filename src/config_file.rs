@@ -0,0 +1,146 @@
+use std::error::Error;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::time::Duration;
+use std::{fs, thread};
+
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use serde_json::Value;
+
+// A config kept in a file, TOML if the name ends in `.toml` and JSON
+// otherwise, so the lights can run from a checked-in config without the
+// Elixir app. The file is loaded over the defaults as a stored config, so
+// older ones are upgraded, see `protocol::load`.
+
+// editors tend to save in several steps, so a change is only read once the
+// file has been left alone for this long
+const SETTLE: Duration = Duration::from_millis(200);
+
+/// Read and parse the config at `path`, see `parse`.
+pub fn load(path: &Path) -> Result<Value, Box<dyn Error>> {
+    let text = fs::read_to_string(path)?;
+    parse(path, &text)
+}
+
+/// Parse `text` as TOML if `path` ends in `.toml`, and as JSON otherwise.
+pub fn parse(path: &Path, text: &str) -> Result<Value, Box<dyn Error>> {
+    let is_toml = path
+        .extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("toml"));
+    Ok(if is_toml {
+        toml::from_str(text)?
+    } else {
+        serde_json::from_str(text)?
+    })
+}
+
+/// Call `changed` with the new contents each time the file changes, for as
+/// long as the returned watcher is kept. The directory is watched rather
+/// than the file so saves that replace the file, as most editors do, are
+/// still seen.
+pub fn watch<F>(path: &Path, mut changed: F) -> notify::Result<RecommendedWatcher>
+where
+    F: FnMut(Result<Value, Box<dyn Error>>) + Send + 'static,
+{
+    let path = path.to_path_buf();
+    let directory = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    let name = path.file_name().map(ToOwned::to_owned);
+
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(tx)?;
+    watcher.watch(&directory, RecursiveMode::NonRecursive)?;
+    thread::spawn(move || {
+        while let Ok(event) = rx.recv() {
+            if !touches(event, &name) {
+                continue;
+            }
+            settle(&rx, SETTLE);
+            changed(load(&path));
+        }
+    });
+    Ok(watcher)
+}
+
+// Swallow events until none have come for `quiet`.
+fn settle<T>(rx: &mpsc::Receiver<T>, quiet: Duration) {
+    while rx.recv_timeout(quiet).is_ok() {}
+}
+
+// Was the file written, created or moved into place? Reads are ignored, or
+// loading the file would set off another load.
+fn touches(event: notify::Result<Event>, name: &Option<OsString>) -> bool {
+    let Ok(event) = event else {
+        return false;
+    };
+    !matches!(event.kind, EventKind::Access(_))
+        && event
+            .paths
+            .iter()
+            .any(|path| path.file_name() == name.as_deref())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let toml = "fade = 0.5\n\n[white]\nhue = 120\nsaturation = 1.0\n";
+        let value = parse(Path::new("lights.toml"), toml).unwrap();
+        assert_eq!(value["fade"], 0.5);
+        assert_eq!(value["white"]["hue"], 120);
+
+        let value = parse(Path::new("lights.json"), r#"{"fade": 0.5}"#).unwrap();
+        assert_eq!(value["fade"], 0.5);
+        assert!(parse(Path::new("lights.json"), toml).is_err());
+    }
+
+    fn event(kind: EventKind, path: &str) -> notify::Result<Event> {
+        Ok(Event::new(kind).add_path(PathBuf::from(path)))
+    }
+
+    #[test]
+    fn test_touches() {
+        use notify::event::{AccessKind, CreateKind, ModifyKind, RenameMode};
+
+        let name = Some(OsString::from("lights.toml"));
+        let written = EventKind::Modify(ModifyKind::Any);
+        assert!(touches(event(written, "config/lights.toml"), &name));
+        assert!(touches(
+            event(EventKind::Create(CreateKind::File), "lights.toml"),
+            &name
+        ));
+        // an editor's temporary file renamed over the config
+        let renamed = Event::new(EventKind::Modify(ModifyKind::Name(RenameMode::Both)))
+            .add_path(PathBuf::from("config/lights.toml~"))
+            .add_path(PathBuf::from("config/lights.toml"));
+        assert!(touches(Ok(renamed), &name));
+
+        assert!(!touches(event(written, "config/other.toml"), &name));
+        assert!(!touches(
+            event(EventKind::Access(AccessKind::Any), "config/lights.toml"),
+            &name
+        ));
+        assert!(!touches(Err(notify::Error::generic("gone")), &name));
+    }
+
+    #[test]
+    fn test_settle() {
+        let (tx, rx) = mpsc::channel();
+        for i in 0..3 {
+            tx.send(i).unwrap();
+        }
+        let start = std::time::Instant::now();
+        settle(&rx, Duration::from_millis(20));
+        assert!(start.elapsed() >= Duration::from_millis(20));
+        assert!(rx.try_recv().is_err());
+
+        // stops waiting once the watcher has gone
+        drop(tx);
+        settle(&rx, Duration::from_secs(60));
+    }
+}
@@ -0,0 +1,155 @@
+use std::time::{Duration, Instant};
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::piano;
+
+// how long a chase takes to cross the keyboard in the test pattern
+const SWEEP: Duration = Duration::from_secs(3);
+// how fast an identified key flashes
+const FLASH: Duration = Duration::from_millis(250);
+
+fn default_identify_seconds() -> f32 {
+    3.0
+}
+
+/// commands accepted on stdin, e.g. `{"id":7,"command":"identify","key":49}`
+#[derive(Deserialize, Debug, Clone, PartialEq, JsonSchema)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum Command {
+    /// stop analysing and hold the last frame
+    Pause,
+    /// back to live analysis from any of the other states
+    Resume,
+    /// turn every light off until resumed
+    Blackout,
+    /// a band of light sweeping along the keys, for checking the strip
+    TestPattern,
+    /// flash a single key (1-88) then go back to what was showing before
+    Identify {
+        /// piano key number, A0 is 1
+        #[schemars(range(min = 1, max = 88))]
+        key: usize,
+        /// how long to flash it for
+        #[serde(default = "default_identify_seconds")]
+        #[schemars(range(min = 0.0, max = 60.0))]
+        seconds: f32,
+    },
+    /// reply with the state and the active config
+    State,
+    /// reply with the current level of every key
+    Snapshot,
+}
+
+/// what the display loop is doing
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum State {
+    /// showing live analysis of the source
+    Live,
+    /// holding the last frame
+    Paused,
+    /// every light off
+    Blackout,
+    /// showing the test pattern
+    TestPattern {
+        /// when the pattern started
+        #[serde(skip)]
+        since: Instant,
+    },
+    /// flashing a single key
+    Identify {
+        /// piano key number, A0 is 1
+        key: usize,
+        /// when to stop flashing it
+        #[serde(skip)]
+        until: Instant,
+        /// what to go back to afterwards
+        #[serde(skip)]
+        previous: Previous,
+    },
+}
+
+/// the state to go back to after identifying a key
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Previous {
+    /// `State::Live`
+    Live,
+    /// `State::Paused`
+    Paused,
+    /// `State::Blackout`
+    Blackout,
+}
+
+impl State {
+    /// what to return to once an identify runs out
+    pub fn previous(&self) -> Previous {
+        match self {
+            State::Paused => Previous::Paused,
+            State::Blackout => Previous::Blackout,
+            State::Identify { previous, .. } => *previous,
+            State::Live | State::TestPattern { .. } => Previous::Live,
+        }
+    }
+}
+
+impl From<Previous> for State {
+    fn from(previous: Previous) -> Self {
+        match previous {
+            Previous::Live => State::Live,
+            Previous::Paused => State::Paused,
+            Previous::Blackout => State::Blackout,
+        }
+    }
+}
+
+/// the bin showing `key`, if it is on the strip
+pub fn key_bin(key: usize) -> Option<usize> {
+    let bin = key.checked_sub(1 + piano::min_key())?;
+    (bin < piano::num_keys()).then_some(bin)
+}
+
+/// light an octave-wide band moving up the keys
+pub fn test_pattern(bins: &mut [f32], elapsed: Duration) {
+    let position = elapsed.as_secs_f32() / SWEEP.as_secs_f32();
+    let centre = position.fract() * bins.len() as f32;
+    for (i, level) in bins.iter_mut().enumerate() {
+        let distance = (i as f32 - centre).abs() / 6.0;
+        *level = (1.0 - distance).max(0.0);
+    }
+}
+
+/// flash a single bin on and off, everything else dark
+pub fn identify(bins: &mut [f32], bin: usize, elapsed: Duration) {
+    bins.fill(0.0);
+    let flashes = elapsed.as_millis() / FLASH.as_millis();
+    if flashes.is_multiple_of(2) {
+        bins[bin] = 1.0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_key_bin() {
+        let first = 2 + piano::min_key();
+        assert_eq!(key_bin(first), Some(1));
+        assert_eq!(key_bin(1), None);
+        assert_eq!(key_bin(piano::bin_key_number(piano::num_keys())), None);
+        let last = piano::num_keys() - 1;
+        assert_eq!(key_bin(piano::bin_key_number(last)), Some(last));
+    }
+
+    #[test]
+    fn test_identify_flashes() {
+        let mut bins = vec![0.5; 10];
+        identify(&mut bins, 3, Duration::ZERO);
+        assert_eq!(bins[3], 1.0);
+        assert_eq!(bins.iter().sum::<f32>(), 1.0);
+        identify(&mut bins, 3, FLASH);
+        assert_eq!(bins[3], 0.0);
+    }
+}
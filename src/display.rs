@@ -1,35 +1,126 @@
 use angular_units::Deg;
 use prisma::{FromColor, Hsv};
 
+use schemars::JsonSchema;
 use serde::{self, Deserialize, Serialize};
-use serde_json::Result;
+use serde_json::{Map, Result, Value};
 
+use crate::beat::BeatEffect;
+use crate::chord::ChordColour;
+use crate::equalisation::Equalisation;
+use crate::piano::{self, Weighting};
+use crate::scale::Overlay;
+use crate::strip::Layout;
+
+/// red, green and blue, 0-255
 pub type Rgb = (u8, u8, u8);
 
-#[derive(Serialize, Deserialize, Debug)]
+/// a colour for keys, the level they're played at sets its brightness
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, JsonSchema)]
 pub struct KeyColour {
+    /// degrees round the colour wheel
+    #[schemars(range(min = 0.0, max = 360.0), extend("x-clamp" = "wrap"))]
     hue: f32,
+    /// 0 for white, 1 for the full colour
+    #[schemars(range(min = 0.0, max = 1.0), extend("x-clamp" = "clamp"))]
     saturation: f32,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+/// what the strip shows
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Mode {
+    /// each key lights with its own level
+    #[default]
+    Keys,
+    /// levels are folded into 12 pitch classes and repeated every octave
+    Chroma,
+    /// the dominant note with a bar showing how sharp or flat it is
+    Tuner,
+    /// the next notes of the lesson loaded from LESSON_FILE
+    Lesson,
+    /// the keys of `overlay` lit dimly under the live levels
+    Overlay,
+}
+
+impl KeyColour {
+    /// `hue` in degrees and `saturation` from 0 to 1
+    pub fn new(hue: f32, saturation: f32) -> Self {
+        KeyColour { hue, saturation }
+    }
+}
+
+/// Bumped whenever a change to the config would stop older stored configs
+/// loading as they are, with a step added to `MIGRATIONS` to upgrade them.
+pub const CONFIG_VERSION: u32 = 1;
+
+// MIGRATIONS[n] upgrades a version n config to version n + 1
+const MIGRATIONS: [fn(&mut Map<String, Value>); CONFIG_VERSION as usize] = [unversioned];
+
+/// How the strip shows what is played. Fields left out, or set to null in a
+/// patch, take their value from `DisplayConfig::default()`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, JsonSchema)]
+#[serde(default)]
 pub struct DisplayConfig {
+    /// the shape of the config, configs without one are version 0
+    #[serde(default = "current_version")]
+    version: u32,
+    /// colour of the white keys
     white: KeyColour,
+    /// colour of the black keys
     black: KeyColour,
+    /// how much of the peak level is kept each frame, at 1 or more peaks
+    /// would never fade
+    #[schemars(range(min = 0.0), extend("exclusiveMaximum" = 1.0))]
     pub fade: f32,
+    /// brightness of the whole strip
+    #[schemars(range(min = 0.0, max = 1.0), extend("x-clamp" = "clamp"))]
     pub brightness: f32,
+    /// gain applied to the key levels before they are shown
+    #[schemars(range(min = 0.0, max = 100.0), extend("x-clamp" = "clamp"))]
     pub sensitivity: f32,
-    // turn on output scaling, makes even quiet sounds
-    // show up
+    /// turn on output scaling, makes even quiet sounds
+    /// show up
     pub scale: bool,
-    // how much to decay values < the max when scaling. bigger means
-    // the display is more "peaky"
+    /// how much to decay values < the max when scaling. bigger means
+    /// the display is more "peaky"
+    #[schemars(range(max = 10.0), extend("exclusiveMinimum" = 0.0))]
     pub decay: f32,
+    /// how energy between two keys is shared out
+    pub weighting: Weighting,
+    /// per-key gain to even out the mic and piano's frequency response
+    pub equalisation: Equalisation,
+    /// what the strip shows
+    pub mode: Mode,
+    /// scale or chord shown in overlay mode
+    pub overlay: Overlay,
+    /// tint the whole strip by the chord being played
+    pub chord_colour: ChordColour,
+    /// colour notes outside the estimated musical key with `out_of_key`
+    pub key_palette: bool,
+    /// colour of notes outside the key when `key_palette` is on
+    pub out_of_key: KeyColour,
+    /// what happens to the display on each beat
+    pub beat_effect: BeatEffect,
+    /// where the keys are on the LED strip and how it's wired
+    pub layout: Layout,
 }
 
-impl DisplayConfig {
-    pub fn default() -> Self {
+fn current_version() -> u32 {
+    CONFIG_VERSION
+}
+
+fn default_out_of_key() -> KeyColour {
+    KeyColour {
+        hue: 200.0,
+        saturation: 1.0,
+    }
+}
+
+impl Default for DisplayConfig {
+    fn default() -> Self {
         DisplayConfig {
+            version: CONFIG_VERSION,
             white: KeyColour {
                 hue: 1.0,
                 saturation: 1.0,
@@ -43,18 +134,125 @@ impl DisplayConfig {
             sensitivity: 1.0,
             decay: 1.8,
             scale: false,
+            weighting: Weighting::default(),
+            equalisation: Equalisation::default(),
+            mode: Mode::default(),
+            overlay: Overlay::default(),
+            chord_colour: ChordColour::default(),
+            key_palette: false,
+            out_of_key: default_out_of_key(),
+            beat_effect: BeatEffect::default(),
+            layout: Layout::default(),
         }
     }
+}
+
+// the fields every config had before it was versioned
+const UNVERSIONED_FIELDS: [&str; 7] = [
+    "white",
+    "black",
+    "fade",
+    "brightness",
+    "sensitivity",
+    "scale",
+    "decay",
+];
+
+/// Is `config` a whole config from before versioning, as the Elixir side
+/// still sends, rather than a patch for the current one?
+pub fn is_unversioned(config: &Value) -> bool {
+    config.get("version").is_none_or(Value::is_null)
+        && UNVERSIONED_FIELDS
+            .iter()
+            .all(|key| config.get(key).is_some())
+}
+
+/// Upgrade a stored config, or a patch for one, to the current version.
+/// Steps only touch the keys that are there, so this works on partial
+/// configs too; missing keys are filled in when the config is applied.
+pub fn migrate(config: &mut Value) -> std::result::Result<(), String> {
+    let Value::Object(config) = config else {
+        return Err("expected a JSON object".to_string());
+    };
+    let version = match config.get("version") {
+        None | Some(Value::Null) => 0,
+        Some(version) => version
+            .as_u64()
+            .ok_or_else(|| format!("version must be a whole number, got {}", version))?,
+    };
+    if version > CONFIG_VERSION as u64 {
+        return Err(format!(
+            "config version {} is newer than this build supports ({})",
+            version, CONFIG_VERSION
+        ));
+    }
+    for step in &MIGRATIONS[version as usize..] {
+        step(config);
+    }
+    config.insert("version".to_string(), CONFIG_VERSION.into());
+    Ok(())
+}
+
+// Version 0 is every config from before the version field. Nothing stopped
+// those holding a fade of 1 or more, or a decay of 0 or less, which are
+// rejected now, so those go back to the defaults.
+fn unversioned(config: &mut Map<String, Value>) {
+    let defaults = DisplayConfig::default();
+    let number = |config: &Map<String, Value>, key| config.get(key).and_then(Value::as_f64);
+    if number(config, "fade").is_some_and(|fade| !(0.0..1.0).contains(&fade)) {
+        config.insert("fade".to_string(), defaults.fade.into());
+    }
+    if number(config, "decay").is_some_and(|decay| decay <= 0.0) {
+        config.insert("decay".to_string(), defaults.decay.into());
+    }
+}
+
+impl DisplayConfig {
+    /// Parse a whole config from JSON. Unlike `protocol::load` this doesn't
+    /// upgrade older configs or check ranges.
     pub fn decode(json: &str) -> Result<Self> {
         serde_json::from_str(json)
     }
+    /// a copy of the config with the white keys set to `hue` and the black
+    /// keys keeping their offset from the white
+    pub fn tinted(&self, hue: f32) -> Self {
+        let offset = self.black.hue - self.white.hue;
+        let mut config = self.clone();
+        config.white.hue = hue;
+        config.black.hue = (hue + offset).rem_euclid(360.0);
+        config
+    }
+    /// a copy of the config with both hues moved round the colour wheel
+    pub fn hue_shifted(&self, degrees: f32) -> Self {
+        self.tinted((self.white.hue + degrees).rem_euclid(360.0))
+    }
+    /// a black key's colour at `intensity`, scaled by the brightness
     pub fn black_colour(&self, intensity: f32) -> Rgb {
         self.set_colour(&self.black, intensity)
     }
+    /// a white key's colour at `intensity`, scaled by the brightness
     pub fn white_colour(&self, intensity: f32) -> Rgb {
         self.set_colour(&self.white, intensity)
     }
 
+    /// the colour of the key shown by `bin_index`, using its entry in
+    /// `colours` in place of white/black if it has one
+    pub fn bin_colour(
+        &self,
+        colours: &[Option<KeyColour>],
+        bin_index: usize,
+        key: piano::KeyColour,
+        intensity: f32,
+    ) -> Rgb {
+        match colours.get(bin_index) {
+            Some(Some(colour)) => self.set_colour(colour, intensity),
+            _ => match key {
+                piano::KeyColour::White => self.white_colour(intensity),
+                piano::KeyColour::Black => self.black_colour(intensity),
+            },
+        }
+    }
+
     fn set_colour(&self, src_colour: &KeyColour, intensity: f32) -> Rgb {
         let colour = Hsv::new(
             Deg(src_colour.hue.clamp(0.0, 359.9)),
@@ -71,12 +269,18 @@ impl DisplayConfig {
     }
 }
 
+/// Something the per-key levels can be shown on: the LED strip, a
+/// terminal, or nothing at all.
 pub trait Display {
+    /// Show `bins`, with the keys that have an entry in `colours` drawn in
+    /// that colour rather than white/black.
     fn visualize_bins(
         &mut self,
         bins: &[f32],
+        colours: &[Option<KeyColour>],
         peak_magnitudes: &mut Vec<f32>,
         config: &DisplayConfig,
     ) -> ();
+    /// Turn everything off, before exiting.
     fn reset(&mut self) -> ();
 }
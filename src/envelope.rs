@@ -0,0 +1,25 @@
+/// Peak hold with an exponential release: the level jumps straight up to a
+/// louder `magnitude` and otherwise falls by a factor of `fade` each frame.
+/// Returns the new level.
+pub fn follow(peak: &mut f32, magnitude: f32, fade: f32) -> f32 {
+    if magnitude > *peak {
+        *peak = magnitude;
+    } else {
+        *peak *= fade;
+    }
+    *peak
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_follow() {
+        let mut peak = 0.0;
+        assert_eq!(follow(&mut peak, 1.0, 0.5), 1.0);
+        assert_eq!(follow(&mut peak, 0.2, 0.5), 0.5);
+        assert_eq!(follow(&mut peak, 0.2, 0.5), 0.25);
+        assert_eq!(follow(&mut peak, 0.3, 0.5), 0.3);
+    }
+}
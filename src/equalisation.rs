@@ -0,0 +1,113 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::piano;
+
+/// Per-key gain applied to the output of `piano::bin_magnitudes` to make up
+/// for microphones and pianos that don't have a flat frequency response.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default, JsonSchema)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum Equalisation {
+    /// every key as it is
+    #[default]
+    Flat,
+    /// one gain per key, starting at key 1 (A0). missing keys get a gain
+    /// of 1.0
+    Keys {
+        /// gains from the lowest key up
+        #[schemars(inner(range(min = 0.0)))]
+        gains: Vec<f32>,
+    },
+    /// one gain per octave, starting at A0. keys between octaves are
+    /// linearly interpolated
+    Octaves {
+        /// gains from the lowest octave up
+        #[schemars(inner(range(min = 0.0)))]
+        gains: Vec<f32>,
+    },
+    /// attenuate the lows and highs the way the ear does
+    AWeighting,
+    /// boost everything below middle C, rising to `1 + amount` at the
+    /// lowest key
+    BassLift {
+        /// extra gain at the lowest key
+        #[schemars(range(min = 0.0, max = 10.0))]
+        amount: f32,
+    },
+}
+
+const MIDDLE_C: usize = 40;
+
+impl Equalisation {
+    /// Scale each key's level by its gain.
+    pub fn apply(&self, bins: &mut [f32]) {
+        if *self == Equalisation::Flat {
+            return;
+        }
+        for (i, val) in bins.iter_mut().enumerate() {
+            *val *= self.gain(piano::bin_key_number(i));
+        }
+    }
+
+    /// the gain for `key_number`, A0 is 1
+    pub fn gain(&self, key_number: usize) -> f32 {
+        match self {
+            Equalisation::Flat => 1.0,
+            Equalisation::Keys { gains } => gains.get(key_number - 1).copied().unwrap_or(1.0),
+            Equalisation::Octaves { gains } => octave_gain(gains, key_number),
+            Equalisation::AWeighting => a_weighting(piano::key_number_to_frequency(key_number)),
+            Equalisation::BassLift { amount } => {
+                let below = MIDDLE_C.saturating_sub(key_number) as f32;
+                1.0 + amount * below / (MIDDLE_C - 1) as f32
+            }
+        }
+    }
+}
+
+fn octave_gain(gains: &[f32], key_number: usize) -> f32 {
+    if gains.is_empty() {
+        return 1.0;
+    }
+    let octave = (key_number - 1) as f32 / 12.0;
+    let lower = (octave.floor() as usize).min(gains.len() - 1);
+    let upper = (lower + 1).min(gains.len() - 1);
+    let t = (octave - lower as f32).clamp(0.0, 1.0);
+    gains[lower] + (gains[upper] - gains[lower]) * t
+}
+
+// IEC 61672 A-weighting as a linear gain
+fn a_weighting(frequency: f32) -> f32 {
+    let f2 = (frequency as f64).powi(2);
+    let ra = 12194.0_f64.powi(2) * f2.powi(2)
+        / ((f2 + 20.6_f64.powi(2))
+            * ((f2 + 107.7_f64.powi(2)) * (f2 + 737.9_f64.powi(2))).sqrt()
+            * (f2 + 12194.0_f64.powi(2)));
+    let db = 20.0 * ra.log10() + 2.0;
+    10.0_f64.powf(db / 20.0) as f32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_octave_gain_interpolates() {
+        let eq = Equalisation::Octaves {
+            gains: vec![2.0, 1.0],
+        };
+        assert_eq!(eq.gain(1), 2.0);
+        assert_eq!(eq.gain(7), 1.5);
+        assert_eq!(eq.gain(13), 1.0);
+        // beyond the last octave holds the last gain
+        assert_eq!(eq.gain(88), 1.0);
+    }
+
+    #[test]
+    fn test_presets() {
+        assert_eq!(Equalisation::BassLift { amount: 1.0 }.gain(1), 2.0);
+        assert_eq!(Equalisation::BassLift { amount: 1.0 }.gain(MIDDLE_C), 1.0);
+        // A-weighting is ~0dB at 1kHz and cuts the bass
+        assert!((Equalisation::AWeighting.gain(64) - 1.0).abs() < 0.05);
+        assert!(Equalisation::AWeighting.gain(20) < 0.1);
+    }
+}
@@ -0,0 +1,239 @@
+use std::io::{self, Read, Write};
+
+use eetf::{Atom, BigInteger, Binary, FixInteger, Float, List, Map, Term};
+use serde_json::{Map as Object, Number, Value};
+
+// Messages in the Erlang external term format, framed the way a port opened
+// with `{:packet, 4}` expects: a 4 byte big-endian length then the term.
+// Terms are converted to and from JSON values so the rest of the protocol
+// doesn't care which format is in use.
+
+/// Read the next packet from `reader`. A packet longer than `limit` is skipped
+/// so the reader stays in step, and reported with its length. Returns `None`
+/// at the end of the input.
+pub fn read_packet<R: Read>(
+    reader: &mut R,
+    limit: usize,
+) -> io::Result<Option<Result<Vec<u8>, usize>>> {
+    let mut length = [0; 4];
+    match reader.read_exact(&mut length) {
+        Ok(()) => {}
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err),
+    }
+    let length = u32::from_be_bytes(length) as usize;
+    if length > limit {
+        io::copy(&mut reader.take(length as u64), &mut io::sink())?;
+        return Ok(Some(Err(length)));
+    }
+    let mut packet = vec![0; length];
+    reader.read_exact(&mut packet)?;
+    Ok(Some(Ok(packet)))
+}
+
+/// Write `packet` with its 4 byte length in front, for `read_packet`.
+pub fn write_packet<W: Write>(writer: &mut W, packet: &[u8]) -> io::Result<()> {
+    let length = u32::try_from(packet.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "packet too long"))?;
+    writer.write_all(&length.to_be_bytes())?;
+    writer.write_all(packet)?;
+    writer.flush()
+}
+
+/// Decode a term into the JSON it stands for.
+pub fn decode(packet: &[u8]) -> Result<Value, String> {
+    let term = Term::decode(packet).map_err(|err| err.to_string())?;
+    to_value(term)
+}
+
+/// Encode JSON as a term, the reverse of `decode`.
+pub fn encode(value: &Value) -> Result<Vec<u8>, String> {
+    let mut packet = Vec::new();
+    from_value(value)?
+        .encode(&mut packet)
+        .map_err(|err| err.to_string())?;
+    Ok(packet)
+}
+
+// Atoms become strings, apart from `true`, `false` and `nil`, so
+// `%{command: :pause}` reads the same as `{"command":"pause"}`. Tuples are
+// read as lists.
+fn to_value(term: Term) -> Result<Value, String> {
+    Ok(match term {
+        Term::Atom(atom) => match atom.name.as_str() {
+            "true" => Value::Bool(true),
+            "false" => Value::Bool(false),
+            "nil" => Value::Null,
+            _ => Value::String(atom.name),
+        },
+        Term::FixInteger(int) => Value::from(int.value),
+        Term::BigInteger(big) => {
+            let number: Number = big
+                .value
+                .to_string()
+                .parse()
+                .map_err(|_| format!("{} is out of range", big.value))?;
+            Value::Number(number)
+        }
+        Term::Float(float) => Value::from(float.value),
+        Term::Binary(binary) => Value::String(
+            String::from_utf8(binary.bytes).map_err(|_| "binary isn't UTF-8".to_string())?,
+        ),
+        Term::List(list) => Value::Array(
+            list.elements
+                .into_iter()
+                .map(to_value)
+                .collect::<Result<_, _>>()?,
+        ),
+        Term::Tuple(tuple) => Value::Array(
+            tuple
+                .elements
+                .into_iter()
+                .map(to_value)
+                .collect::<Result<_, _>>()?,
+        ),
+        Term::Map(map) => {
+            let mut object = Object::new();
+            for (key, value) in map.entries {
+                let key = match key {
+                    Term::Atom(atom) => atom.name,
+                    Term::Binary(binary) => String::from_utf8(binary.bytes)
+                        .map_err(|_| "map key isn't UTF-8".to_string())?,
+                    other => return Err(format!("unsupported map key {}", other)),
+                };
+                object.insert(key, to_value(value)?);
+            }
+            Value::Object(object)
+        }
+        other => return Err(format!("unsupported term {}", other)),
+    })
+}
+
+// Objects become maps with binary keys and strings become binaries, so
+// replies arrive in Elixir as e.g. `%{"id" => 7, "reply" => %{"state" =>
+// "paused"}}`. Keys are never atoms: the Elixir side decodes with `[:safe]`,
+// which refuses atoms the VM hasn't seen, and reports keep gaining keys.
+fn from_value(value: &Value) -> Result<Term, String> {
+    Ok(match value {
+        Value::Null => Term::from(Atom::from("nil")),
+        Value::Bool(b) => Term::from(Atom::from(if *b { "true" } else { "false" })),
+        Value::Number(number) => {
+            if let Some(int) = number.as_i64() {
+                match i32::try_from(int) {
+                    Ok(int) => Term::from(FixInteger::from(int)),
+                    Err(_) => Term::from(BigInteger::from(int)),
+                }
+            } else if let Some(int) = number.as_u64() {
+                Term::from(BigInteger::from(int))
+            } else {
+                let float = number.as_f64().unwrap_or(f64::NAN);
+                Term::from(Float::try_from(float).map_err(|err| err.to_string())?)
+            }
+        }
+        Value::String(s) => Term::from(Binary::from(s.as_bytes())),
+        Value::Array(values) => Term::from(List::from(
+            values
+                .iter()
+                .map(from_value)
+                .collect::<Result<Vec<_>, _>>()?,
+        )),
+        Value::Object(object) => Term::from(Map::from(
+            object
+                .iter()
+                .map(|(key, value)| {
+                    Ok((Term::from(Binary::from(key.as_bytes())), from_value(value)?))
+                })
+                .collect::<Result<Vec<_>, String>>()?,
+        )),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_round_trip() {
+        let value = json!({
+            "id": 7,
+            "reply": {"state": "paused", "levels": [0.5, 1.0], "big": 5_000_000_000i64},
+            "ok": true,
+            "missing": null,
+        });
+        let packet = encode(&value).unwrap();
+        assert_eq!(decode(&packet).unwrap(), value);
+    }
+
+    #[test]
+    fn test_atoms_and_tuples() {
+        let term = Term::from(Map::from(vec![
+            (
+                Term::from(Atom::from("command")),
+                Term::from(Atom::from("pause")),
+            ),
+            (
+                Term::from(Binary::from("pair".as_bytes())),
+                Term::from(eetf::Tuple::from(vec![
+                    Term::from(FixInteger::from(1)),
+                    Term::from(Atom::from("false")),
+                ])),
+            ),
+        ]));
+        let mut packet = Vec::new();
+        term.encode(&mut packet).unwrap();
+        let value = decode(&packet).unwrap();
+        assert_eq!(value, json!({"command": "pause", "pair": [1, false]}));
+    }
+
+    // every atom in `term`, `[:safe]` decoding only allows ones that exist
+    fn atoms(term: &Term, found: &mut Vec<String>) {
+        match term {
+            Term::Atom(atom) => found.push(atom.name.clone()),
+            Term::List(list) => list.elements.iter().for_each(|t| atoms(t, found)),
+            Term::Tuple(tuple) => tuple.elements.iter().for_each(|t| atoms(t, found)),
+            Term::Map(map) => {
+                for (key, value) in &map.entries {
+                    atoms(key, found);
+                    atoms(value, found);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    #[test]
+    fn test_reports_are_safe() {
+        use crate::control::State;
+        use crate::display::DisplayConfig;
+        use crate::telemetry::Telemetry;
+        use std::time::Duration;
+
+        let mut telemetry = Telemetry::new(Duration::ZERO);
+        telemetry.frame(Duration::from_millis(3));
+        let status = telemetry.status(&DisplayConfig::default(), None, vec![49], State::Paused);
+        let report = json!({"telemetry": status, "ok": true, "missing": null});
+        let term = Term::decode(&encode(&report).unwrap()[..]).unwrap();
+        let mut found = Vec::new();
+        atoms(&term, &mut found);
+        found.sort();
+        found.dedup();
+        assert_eq!(found, ["nil", "true"]);
+        assert_eq!(to_value(term).unwrap(), report);
+    }
+
+    #[test]
+    fn test_packets() {
+        let mut stream = Vec::new();
+        write_packet(&mut stream, b"hello").unwrap();
+        write_packet(&mut stream, &[0; 100]).unwrap();
+        write_packet(&mut stream, b"again").unwrap();
+
+        let mut reader = &stream[..];
+        let read = |reader: &mut &[u8]| read_packet(reader, 10).unwrap();
+        assert_eq!(read(&mut reader), Some(Ok(b"hello".to_vec())));
+        assert_eq!(read(&mut reader), Some(Err(100)));
+        assert_eq!(read(&mut reader), Some(Ok(b"again".to_vec())));
+        assert_eq!(read(&mut reader), None);
+    }
+}
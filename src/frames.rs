@@ -0,0 +1,280 @@
+use std::error::Error;
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+use std::time::{Duration, Instant};
+
+use crate::display::DisplayConfig;
+use crate::report;
+
+const MAGIC: &[u8; 4] = b"BLFR";
+const VERSION: u8 = 1;
+const CONFIG: u8 = b'C';
+const FRAME: u8 = b'F';
+// levels are stored as u16 fixed point covering 0..MAX_LEVEL
+const MAX_LEVEL: f32 = 4.0;
+const FLUSH_EVERY: Duration = Duration::from_secs(1);
+
+// A capture file is the magic and version followed by records, each a tag
+// byte and a little-endian body:
+//   config: u32 length, then the `DisplayConfig` as JSON
+//   frame:  u32 milliseconds since the start, u16 bin count, u16 per bin
+#[derive(Debug, Clone, PartialEq)]
+enum Record {
+    Config(String),
+    Frame { millis: u32, bins: Vec<u16> },
+}
+
+fn quantise(level: f32) -> u16 {
+    (level.clamp(0.0, MAX_LEVEL) / MAX_LEVEL * u16::MAX as f32).round() as u16
+}
+
+fn dequantise(value: u16) -> f32 {
+    value as f32 / u16::MAX as f32 * MAX_LEVEL
+}
+
+/// Writes the per-key levels from the analysis, and the config whenever it
+/// changes, to a capture file.
+pub struct Recorder {
+    file: BufWriter<File>,
+    started: Instant,
+    last_flush: Instant,
+    // the config last written, it's only serialised again when it changes
+    last_config: Option<DisplayConfig>,
+}
+
+impl Recorder {
+    /// Start a new capture at `path`, replacing any file already there.
+    pub fn create(path: &str) -> io::Result<Self> {
+        let mut file = BufWriter::new(File::create(path)?);
+        file.write_all(MAGIC)?;
+        file.write_all(&[VERSION])?;
+        Ok(Recorder {
+            file,
+            started: Instant::now(),
+            last_flush: Instant::now(),
+            last_config: None,
+        })
+    }
+
+    /// Add a frame's levels, and the config if it has changed since the
+    /// last one. Failures are logged rather than stopping the lights.
+    pub fn record(&mut self, bins: &[f32], config: &DisplayConfig) {
+        if let Err(err) = self.write(bins, config) {
+            report::log(&format!("failed to record frame: {}", err));
+        }
+    }
+
+    fn write(&mut self, bins: &[f32], config: &DisplayConfig) -> Result<(), Box<dyn Error>> {
+        if self.last_config.as_ref() != Some(config) {
+            let json = serde_json::to_string(config)?;
+            write_record(&mut self.file, &Record::Config(json))?;
+            self.last_config = Some(config.clone());
+        }
+        let frame = Record::Frame {
+            millis: self.started.elapsed().as_millis() as u32,
+            bins: bins.iter().map(|&level| quantise(level)).collect(),
+        };
+        write_record(&mut self.file, &frame)?;
+        if self.last_flush.elapsed() >= FLUSH_EVERY {
+            self.last_flush = Instant::now();
+            self.file.flush()?;
+        }
+        Ok(())
+    }
+}
+
+fn write_record<W: Write>(out: &mut W, record: &Record) -> io::Result<()> {
+    match record {
+        Record::Config(json) => {
+            out.write_all(&[CONFIG])?;
+            out.write_all(&(json.len() as u32).to_le_bytes())?;
+            out.write_all(json.as_bytes())
+        }
+        Record::Frame { millis, bins } => {
+            out.write_all(&[FRAME])?;
+            out.write_all(&millis.to_le_bytes())?;
+            out.write_all(&(bins.len() as u16).to_le_bytes())?;
+            for value in bins {
+                out.write_all(&value.to_le_bytes())?;
+            }
+            Ok(())
+        }
+    }
+}
+
+fn read_records<R: Read>(input: &mut R) -> Result<Vec<Record>, Box<dyn Error>> {
+    let mut header = [0u8; 5];
+    input.read_exact(&mut header)?;
+    if &header[..4] != MAGIC || header[4] != VERSION {
+        return Err("not a blinken lights capture".into());
+    }
+
+    let mut records = Vec::new();
+    let mut tag = [0u8; 1];
+    loop {
+        match input.read_exact(&mut tag) {
+            Ok(()) => {}
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(err) => return Err(err.into()),
+        }
+        let record = match tag[0] {
+            CONFIG => {
+                let len = read_u32(input)? as usize;
+                let mut json = vec![0u8; len];
+                input.read_exact(&mut json)?;
+                Record::Config(String::from_utf8(json)?)
+            }
+            FRAME => {
+                let millis = read_u32(input)?;
+                let mut len = [0u8; 2];
+                input.read_exact(&mut len)?;
+                let mut bins = Vec::with_capacity(u16::from_le_bytes(len) as usize);
+                for _ in 0..u16::from_le_bytes(len) {
+                    let mut value = [0u8; 2];
+                    input.read_exact(&mut value)?;
+                    bins.push(u16::from_le_bytes(value));
+                }
+                Record::Frame { millis, bins }
+            }
+            other => return Err(format!("unknown record {:?}", other as char).into()),
+        };
+        records.push(record);
+    }
+    Ok(records)
+}
+
+fn read_u32<R: Read>(input: &mut R) -> io::Result<u32> {
+    let mut bytes = [0u8; 4];
+    input.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+/// Plays a capture back in real time, looping at the end.
+pub struct Replay {
+    records: Vec<Record>,
+    next: usize,
+    position: Duration,
+    // recorded configs are only applied on the first pass, so changes made
+    // over stdin while iterating on a capture stick
+    first_pass: bool,
+}
+
+impl Replay {
+    /// Read a whole capture written by a `Recorder`.
+    pub fn load(path: &str) -> Result<Self, Box<dyn Error>> {
+        let mut file = io::BufReader::new(File::open(path)?);
+        Ok(Replay {
+            records: read_records(&mut file)?,
+            next: 0,
+            position: Duration::ZERO,
+            first_pass: true,
+        })
+    }
+
+    /// Move on by `elapsed`, leaving the latest frame in `bins`. Returns the
+    /// recorded config if it changed.
+    pub fn advance(&mut self, elapsed: Duration, bins: &mut [f32]) -> Option<DisplayConfig> {
+        self.position += elapsed;
+        let mut config = None;
+        while let Some(record) = self.records.get(self.next) {
+            match record {
+                Record::Frame { millis, .. } if *millis as u128 > self.position.as_millis() => {
+                    break;
+                }
+                Record::Frame { bins: values, .. } => {
+                    bins.fill(0.0);
+                    for (level, &value) in bins.iter_mut().zip(values) {
+                        *level = dequantise(value);
+                    }
+                }
+                Record::Config(json) if self.first_pass => match DisplayConfig::decode(json) {
+                    Ok(decoded) => config = Some(decoded),
+                    Err(err) => report::log(&format!("skipping recorded config: {}", err)),
+                },
+                Record::Config(_) => {}
+            }
+            self.next += 1;
+        }
+        if self.next >= self.records.len() && !self.records.is_empty() {
+            self.next = 0;
+            self.position = Duration::ZERO;
+            self.first_pass = false;
+        }
+        config
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_records_round_trip() {
+        let records = vec![
+            Record::Config("{}".to_string()),
+            Record::Frame {
+                millis: 4,
+                bins: vec![0, quantise(0.5), u16::MAX],
+            },
+        ];
+        let mut bytes = MAGIC.to_vec();
+        bytes.push(VERSION);
+        for record in &records {
+            write_record(&mut bytes, record).unwrap();
+        }
+        assert_eq!(read_records(&mut bytes.as_slice()).unwrap(), records);
+        assert!((dequantise(quantise(0.5)) - 0.5).abs() < 0.001);
+
+        assert!(read_records(&mut &b"nope!"[..]).is_err());
+    }
+
+    #[test]
+    fn test_config_written_on_change() {
+        let path = std::env::temp_dir().join(format!("leds-frames-{}.blfr", std::process::id()));
+        let mut recorder = Recorder::create(path.to_str().unwrap()).unwrap();
+        let mut config = DisplayConfig::default();
+        recorder.record(&[0.5], &config);
+        recorder.record(&[0.5], &config);
+        config.fade = 0.5;
+        recorder.record(&[0.5], &config);
+        drop(recorder);
+
+        let records = read_records(&mut File::open(&path).unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let configs = records
+            .iter()
+            .filter(|record| matches!(record, Record::Config(_)))
+            .count();
+        assert_eq!(records.len(), 5);
+        assert_eq!(configs, 2);
+    }
+
+    #[test]
+    fn test_replay_in_time() {
+        let config = serde_json::to_string(&DisplayConfig::default()).unwrap();
+        let frame = |millis, level| Record::Frame {
+            millis,
+            bins: vec![quantise(level)],
+        };
+        let mut replay = Replay {
+            records: vec![Record::Config(config), frame(0, 0.25), frame(100, 0.75)],
+            next: 0,
+            position: Duration::ZERO,
+            first_pass: true,
+        };
+        let mut bins = [0.0];
+        assert!(replay.advance(Duration::ZERO, &mut bins).is_some());
+        assert!((bins[0] - 0.25).abs() < 0.001);
+        assert!(
+            replay
+                .advance(Duration::from_millis(50), &mut bins)
+                .is_none()
+        );
+        assert!((bins[0] - 0.25).abs() < 0.001);
+        replay.advance(Duration::from_millis(50), &mut bins);
+        assert!((bins[0] - 0.75).abs() < 0.001);
+        // looped, and the config isn't applied again
+        assert!(!replay.first_pass);
+        assert!(replay.advance(Duration::ZERO, &mut bins).is_none());
+    }
+}
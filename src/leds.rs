@@ -1,68 +1,35 @@
-#![allow(dead_code)]
 use smart_leds::RGB8;
 use ws281x_rpi::Ws2812Rpi;
 
 use crate::display;
-use crate::piano::{KeyColour, key_colour};
-
-const NUM_LEDS: usize = 144;
-const PIN: i32 = 10;
+use crate::strip::{Layout, render};
 
+/// A WS2812 strip driven from a Raspberry Pi.
 pub struct LEDs {
     leds: Ws2812Rpi,
     data: Vec<RGB8>,
 }
 
 impl LEDs {
-    pub fn new() -> Self {
-        let ws = Ws2812Rpi::new(NUM_LEDS as i32, PIN).unwrap();
+    /// Open the strip described by `layout`, panicking if it can't be.
+    pub fn new(layout: &Layout) -> Self {
+        let ws = Ws2812Rpi::new(layout.num_leds as i32, layout.pin).unwrap();
         LEDs {
             leds: ws,
-            data: vec![RGB8::default(); NUM_LEDS],
+            data: vec![RGB8::default(); layout.num_leds],
         }
     }
-    fn set_colour(&mut self, l: usize, rgb: display::Rgb) {
-        let (r, g, b) = rgb;
-        self.data[l].r = r;
-        self.data[l].g = g;
-        self.data[l].b = b;
-    }
 }
 
 impl display::Display for LEDs {
     fn visualize_bins(
         &mut self,
         bins: &[f32],
+        colours: &[Option<display::KeyColour>],
         peak_magnitudes: &mut Vec<f32>,
         config: &display::DisplayConfig,
     ) {
-        // offset because we don't use all the leds
-        let mut l: usize = 5;
-        for (i, &magnitude) in bins.iter().enumerate() {
-            if l >= NUM_LEDS {
-                panic!("led index out of bounds {}", l);
-            }
-            if magnitude > peak_magnitudes[i] {
-                peak_magnitudes[i] = magnitude;
-            } else {
-                peak_magnitudes[i] *= config.fade;
-            }
-            let brightness = peak_magnitudes[i] * config.sensitivity;
-            match key_colour(i + 1) {
-                KeyColour::White => {
-                    let rgb = config.white_colour(brightness);
-                    self.set_colour(l, rgb);
-                    self.set_colour(l + 1, rgb);
-                    self.set_colour(l + 2, rgb);
-                    l += 3;
-                }
-                KeyColour::Black => {
-                    let rgb = config.black_colour(brightness);
-                    self.set_colour(l, rgb);
-                    l += 1;
-                }
-            }
-        }
+        render(bins, colours, peak_magnitudes, config, &mut self.data);
         smart_leds::SmartLedsWrite::write(
             &mut self.leds,
             smart_leds::gamma(self.data.iter().copied()), // self.data.iter().copied(),
@@ -71,7 +38,7 @@ impl display::Display for LEDs {
     }
     fn reset(&mut self) {
         // self.data.fill(RGB8::default());
-        let blank = vec![RGB8::default(); NUM_LEDS];
+        let blank = vec![RGB8::default(); self.data.len()];
         let _ = smart_leds::SmartLedsWrite::write(
             &mut self.leds,
             // smart_leds::gamma(self.data.iter().copied()),
@@ -0,0 +1,243 @@
+use std::error::Error;
+
+use serde::Serialize;
+
+use crate::analysis::Input;
+use crate::display::KeyColour;
+use crate::midi_output::NoteDetector;
+use crate::piano;
+use crate::playback::{self, Event};
+use crate::report;
+
+// notes starting within this many seconds of each other are played together
+const CHORD_WINDOW: f32 = 0.05;
+// how brightly the keys to play now, and the ones after, are lit
+const TARGET_LEVEL: f32 = 0.6;
+const PREVIEW_LEVEL: f32 = 0.15;
+const TARGET_HUE: f32 = 120.0;
+const WRONG_HUE: f32 = 0.0;
+
+#[derive(Serialize)]
+struct Report {
+    lesson: LessonReport,
+}
+
+#[derive(Serialize)]
+struct LessonReport {
+    step: usize,
+    steps: usize,
+    complete: bool,
+}
+
+/// Follows a student through a piece: lights the keys to play next and only
+/// moves on once they've all been played.
+pub struct Lesson {
+    // key numbers to play at each step
+    steps: Vec<Vec<usize>>,
+    step: usize,
+    // keys of the current step played so far
+    hit: Vec<usize>,
+    // bins pressed during this step that aren't part of it
+    wrong: Vec<bool>,
+    detector: NoteDetector,
+}
+
+/// Load a lesson from a MIDI file, a step for each chord or note in it.
+pub fn load(path: &str) -> Result<Lesson, Box<dyn Error>> {
+    Ok(Lesson::new(steps(&playback::load(path)?)))
+}
+
+// Group the note-ons of a piece into the chords and single notes to be
+// played in turn. Notes on keys the strip doesn't cover can never be
+// detected, so they're left out, and so is a step made only of them.
+fn steps(events: &[Event]) -> Vec<Vec<usize>> {
+    let keys = piano::bin_key_number(0)..=piano::bin_key_number(piano::num_keys() - 1);
+    let mut steps: Vec<Vec<usize>> = Vec::new();
+    let mut step_time = f32::MIN;
+    for event in events {
+        let [status, note, velocity] = event.message;
+        let key_number = (note as usize).wrapping_sub(20);
+        if status & 0xF0 != 0x90 || velocity == 0 || !keys.contains(&key_number) {
+            continue;
+        }
+        match steps.last_mut() {
+            Some(step) if event.time - step_time <= CHORD_WINDOW => {
+                if !step.contains(&key_number) {
+                    step.push(key_number);
+                }
+            }
+            _ => {
+                step_time = event.time;
+                steps.push(vec![key_number]);
+            }
+        }
+    }
+    steps
+}
+
+impl Lesson {
+    fn new(steps: Vec<Vec<usize>>) -> Self {
+        Lesson {
+            steps,
+            step: 0,
+            hit: Vec::new(),
+            wrong: Vec::new(),
+            detector: NoteDetector::new(),
+        }
+    }
+
+    /// Take the levels being played, move the lesson on if the current step
+    /// is done, and replace the levels with what the student should see.
+    /// Notes are picked out of the levels as `NoteDetector::update` does.
+    pub fn update(&mut self, bins: &mut [f32], input: Input) -> Vec<Option<KeyColour>> {
+        self.wrong.resize(bins.len(), false);
+        let first_key = piano::bin_key_number(0);
+
+        for note in self.detector.update(bins, input) {
+            let bin = note.key_number - first_key;
+            if note.velocity == 0 {
+                self.wrong[bin] = false;
+            } else if self.current().contains(&note.key_number) {
+                if !self.hit.contains(&note.key_number) {
+                    self.hit.push(note.key_number);
+                }
+            } else {
+                self.wrong[bin] = true;
+            }
+        }
+
+        if self.step < self.steps.len() && self.hit.len() == self.current().len() {
+            self.step += 1;
+            self.hit.clear();
+            self.wrong.fill(false);
+            report::send(&Report {
+                lesson: LessonReport {
+                    step: self.step,
+                    steps: self.steps.len(),
+                    complete: self.step == self.steps.len(),
+                },
+            });
+        }
+
+        bins.fill(0.0);
+        let mut colours = vec![None; bins.len()];
+        let target = KeyColour::new(TARGET_HUE, 1.0);
+        let mut light = |key_number: usize, level: f32, colour: Option<KeyColour>| {
+            if let Some(bin) = key_number.checked_sub(first_key)
+                && bin < bins.len()
+            {
+                bins[bin] = level;
+                colours[bin] = colour;
+            }
+        };
+        if let Some(next) = self.steps.get(self.step + 1) {
+            for &key_number in next {
+                light(key_number, PREVIEW_LEVEL, Some(target.clone()));
+            }
+        }
+        for &key_number in self.current() {
+            if self.hit.contains(&key_number) {
+                light(key_number, 1.0, None);
+            } else {
+                light(key_number, TARGET_LEVEL, Some(target.clone()));
+            }
+        }
+        for (bin, _) in self.wrong.iter().enumerate().filter(|(_, w)| **w) {
+            light(bin + first_key, 1.0, Some(KeyColour::new(WRONG_HUE, 1.0)));
+        }
+        colours
+    }
+
+    fn current(&self) -> &[usize] {
+        self.steps
+            .get(self.step)
+            .map_or(&[], |step| step.as_slice())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOUD: Input = Input::Audio { rms: 0.1 };
+
+    fn note_on(time: f32, note: u8) -> Event {
+        Event {
+            time,
+            message: [0x90, note, 100],
+        }
+    }
+
+    #[test]
+    fn test_steps_group_chords() {
+        let events = [
+            note_on(0.0, 60),
+            note_on(0.01, 64),
+            Event {
+                time: 0.2,
+                message: [0x80, 60, 0],
+            },
+            note_on(0.5, 62),
+        ];
+        assert_eq!(steps(&events), vec![vec![40, 44], vec![42]]);
+    }
+
+    #[test]
+    fn test_steps_skip_keys_off_the_strip() {
+        let lowest = piano::bin_key_number(0);
+        let highest = piano::bin_key_number(piano::num_keys() - 1);
+        let midi = |key_number: usize| (key_number + 20) as u8;
+        let events = [
+            // a left-hand bass note under a right-hand chord
+            note_on(0.0, midi(lowest - 12)),
+            note_on(0.0, 60),
+            note_on(0.01, 64),
+            // a bass note on its own
+            note_on(0.5, midi(lowest - 1)),
+            note_on(1.0, midi(lowest)),
+            note_on(1.5, midi(highest + 1)),
+            note_on(2.0, midi(highest)),
+        ];
+        assert_eq!(
+            steps(&events),
+            vec![vec![40, 44], vec![lowest], vec![highest]]
+        );
+    }
+
+    #[test]
+    fn test_advances_on_correct_notes() {
+        let mut lesson = Lesson::new(vec![vec![40, 44], vec![42]]);
+        let first_key = piano::bin_key_number(0);
+        let mut bins = vec![0.0; piano::num_keys()];
+
+        let colours = lesson.update(&mut bins, LOUD);
+        assert_eq!(bins[40 - first_key], TARGET_LEVEL);
+        assert_eq!(bins[42 - first_key], PREVIEW_LEVEL);
+        assert!(colours[40 - first_key].is_some());
+
+        // a wrong note doesn't advance and is shown
+        bins.fill(0.0);
+        bins[41 - first_key] = 1.0;
+        let colours = lesson.update(&mut bins, LOUD);
+        assert_eq!(lesson.step, 0);
+        assert_eq!(bins[41 - first_key], 1.0);
+        assert!(colours[41 - first_key].is_some());
+
+        bins.fill(0.0);
+        bins[40 - first_key] = 1.0;
+        bins[44 - first_key] = 1.0;
+        lesson.update(&mut bins, LOUD);
+        assert_eq!(lesson.step, 1);
+    }
+
+    #[test]
+    fn test_soft_midi_notes_advance() {
+        let mut lesson = Lesson::new(vec![vec![40], vec![42]]);
+        let first_key = piano::bin_key_number(0);
+        let mut bins = vec![0.0; piano::num_keys()];
+        // velocity 30
+        bins[40 - first_key] = 30.0 / 127.0;
+        lesson.update(&mut bins, Input::Midi);
+        assert_eq!(lesson.step, 1);
+    }
+}
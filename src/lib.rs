@@ -0,0 +1,73 @@
+//! The analysis and colour pipeline behind the blinken lights.
+//!
+//! Audio from an [`audio::AudioInput`] (or MIDI, or a replayed capture) is
+//! turned into one level per piano key by [`analysis::analyse`], followed by
+//! the chord, key and beat trackers, and drawn on anything implementing
+//! [`display::Display`]. [`pipeline::Pipeline`] ties the whole thing
+//! together; the `leds` binary is a thin wrapper around it.
+
+#![warn(missing_docs)]
+
+/// Per-key levels from a window of audio samples.
+pub mod analysis;
+/// Capturing audio from the default input device.
+pub mod audio;
+/// Tempo and beat tracking, and what the display does on the beat.
+pub mod beat;
+/// Recognising the chord being played from the chroma.
+pub mod chord;
+/// Levels folded into the 12 pitch classes.
+pub mod chroma;
+/// Configs kept in TOML or JSON files, reloaded when they change.
+pub mod config_file;
+/// Commands accepted on stdin and the display states they lead to.
+pub mod control;
+/// How levels are shown, and the `Display` trait that shows them.
+pub mod display;
+/// Peak following, so lights fade out rather than switching off.
+pub mod envelope;
+/// Per-key gain to even out the frequency response.
+pub mod equalisation;
+/// The Erlang external term format, an alternative to JSON on stdin.
+pub mod etf;
+/// Recording levels to a file and replaying them.
+pub mod frames;
+/// The WS2812 LED strip on a Raspberry Pi.
+#[cfg(feature = "leds")]
+pub mod leds;
+/// Lesson mode, stepping through the notes of a MIDI file.
+pub mod lesson;
+/// MIDI input, as levels from the keys held.
+pub mod midi;
+/// Notes picked out of the levels, sent on as MIDI.
+pub mod midi_output;
+/// Estimating the musical key from the chroma.
+pub mod musical_key;
+/// A display that shows nothing.
+pub mod null;
+/// Keys, frequencies and sharing spectral energy out over the keys.
+pub mod piano;
+/// Everything from a source of levels to a display, frame by frame.
+pub mod pipeline;
+/// Playing a MIDI file into a keyboard.
+pub mod playback;
+/// A copy of the LED colours for a preview in the web UI.
+pub mod preview;
+/// Messages on stdin, and the errors and replies sent back.
+pub mod protocol;
+/// Messages sent to the Elixir side, and log messages.
+pub mod report;
+/// Overlay mode, lighting the notes of a scale or chord.
+pub mod scale;
+/// Statistics on each session of playing.
+pub mod session;
+/// How the keys are laid out along the LED strip.
+pub mod strip;
+/// Regular reports on how the pipeline is keeping up.
+pub mod telemetry;
+/// A display that prints to the terminal.
+pub mod terminal;
+/// Tuner mode, showing how sharp or flat the loudest note is.
+pub mod tuner;
+/// The JSON Schema for the protocol, and range checks driven by it.
+pub mod validation;
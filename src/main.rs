@@ -1,44 +1,28 @@
-use std::io::{self, BufRead, BufReader};
+use std::io::{self, BufReader};
+use std::path::PathBuf;
 use std::time::Duration;
 use std::{env, panic, process, thread};
 
-use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use std::sync::{Arc, Mutex, mpsc};
 
-use std::sync::{mpsc, Arc, Mutex};
-
-use spectrum_analyzer::scaling::{
-    combined,
-    // divide_by_N,
-    divide_by_N_sqrt,
-    scale_20_times_log10,
-    scale_to_zero_to_one,
+use leds::display::{self, Display, DisplayConfig};
+use leds::pipeline::{Pipeline, Source};
+use leds::strip::Layout;
+use leds::{
+    audio, config_file, control, frames, lesson, midi, midi_output, piano, playback, preview,
+    protocol, report, session, telemetry, validation,
 };
-use spectrum_analyzer::windows::hann_window;
-use spectrum_analyzer::{samples_fft_to_spectrum, FrequencyLimit};
-
-use ringbuf::traits::*;
-
-mod display;
-mod leds;
-mod null;
-mod piano;
-mod terminal;
-
-use crate::display::{Display, DisplayConfig};
-
-const SAMPLE_SIZE: usize = 2usize.pow(13);
-const RINGBUFFER_SIZE: usize = SAMPLE_SIZE;
 
-enum Ping {
-    Audio,
-    Timeout,
-}
+const DEFAULT_PREVIEW_FPS: f32 = 15.0;
 
 struct ConfigWrapper {
     config: DisplayConfig,
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // before any other thread starts, so they all inherit the mask
+    let signals = block_shutdown_signals();
+
     let orig_hook = panic::take_hook();
     panic::set_hook(Box::new(move |panic_info| {
         // invoke the default handler and exit the process
@@ -46,129 +30,228 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         process::exit(1);
     }));
 
-    let load_config = if let Ok(json) = env::var("DISPLAY_CONFIG") {
+    // stdout only carries messages for the Elixir side, logs go to stderr
+    let format = match env::var("CONTROL_FORMAT") {
+        Ok(name) => report::Format::from_name(&name)
+            .ok_or_else(|| format!("unknown CONTROL_FORMAT {}, expected json or etf", name))?,
+        Err(_) => report::Format::Json,
+    };
+    report::set_format(format);
+    report::set_sink(io::stdout());
+    report::set_logger(|message| eprintln!("{}", message));
+
+    // print the schema for the config and the messages on stdin, for the
+    // web UI to build its controls from
+    if env::args().any(|arg| arg == "--schema") {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&validation::protocol_schema())?
+        );
+        return Ok(());
+    }
+
+    // `leds [--schema] [CONFIG_FILE]`
+    let config_path = env::args()
+        .skip(1)
+        .find(|arg| !arg.starts_with("--"))
+        .map(PathBuf::from);
+
+    // Upgraded and held to the same ranges as updates on stdin. A config
+    // that still won't load stops here rather than quietly running with
+    // the defaults.
+    let load_config = if let Some(path) = &config_path {
+        eprintln!("Using config from {}", path.display());
+        let patch = config_file::load(path)
+            .map_err(|err| format!("can't read {}: {}", path.display(), err))?;
+        protocol::load(&patch).map_err(|err| config_error(&path.display().to_string(), err))?
+    } else if let Ok(json) = env::var("DISPLAY_CONFIG") {
         eprintln!("Using config from DISPLAY_CONFIG");
-        if let Ok(config) = DisplayConfig::decode(&json) {
-            config
-        } else {
-            eprintln!("DisplayConfig has error, using default");
-            DisplayConfig::default()
-        }
+        let patch: serde_json::Value = serde_json::from_str(&json)
+            .map_err(|err| format!("DISPLAY_CONFIG isn't valid JSON: {}", err))?;
+        protocol::load(&patch).map_err(|err| config_error("DISPLAY_CONFIG", err))?
     } else {
         eprintln!("Using default config");
         DisplayConfig::default()
     };
+    // the strip is opened once, so later changes to its size or pin need a
+    // restart
+    let layout = load_config.layout;
     let display_config = Arc::new(Mutex::new(ConfigWrapper {
         config: load_config,
     }));
     let display_config_read = Arc::clone(&display_config);
     let display_config_write = Arc::clone(&display_config);
 
-    let (tx, rx) = mpsc::channel();
-    let num_bins: usize = piano::num_keys();
-    println!("num_bins: {}", num_bins);
-    let ringbuf = ringbuf::HeapRb::<f32>::new(RINGBUFFER_SIZE);
-
-    let shared_buffer = Arc::new(Mutex::new(ringbuf));
-
-    let producer_buffer = Arc::clone(&shared_buffer);
-    let consumer_buffer = Arc::clone(&shared_buffer);
-
-    let host = cpal::default_host();
-    let device = host
-        .default_input_device()
-        .expect("no input device available");
-
-    let config = device
-        .default_input_config()
-        .expect("no default input config");
+    // Saving the file replaces the whole config, so the file stays the
+    // source of truth over anything changed on stdin in the meantime. A
+    // bad save is reported and the lights carry on as they were.
+    let _watcher = match &config_path {
+        Some(path) => {
+            let display_config = Arc::clone(&display_config);
+            let name = path.display().to_string();
+            let watcher = config_file::watch(path, move |patch| {
+                let config = match patch {
+                    Ok(patch) => protocol::load(&patch).map_err(|err| config_error(&name, err)),
+                    Err(err) => Err(format!("can't read {}: {}", name, err)),
+                };
+                match config {
+                    Ok(config) => {
+                        eprintln!("Reloaded config from {}", name);
+                        if let Ok(mut wrapper) = display_config.lock() {
+                            wrapper.config = config;
+                        }
+                    }
+                    Err(message) => eprintln!("Kept the previous config: {}", message),
+                }
+            })
+            .map_err(|err| format!("can't watch {}: {}", path.display(), err))?;
+            Some(watcher)
+        }
+        None => None,
+    };
 
-    let mut stream_config: cpal::StreamConfig = config.into();
+    let num_bins: usize = piano::num_keys();
+    eprintln!("num_bins: {}", num_bins);
+
+    // the input handles have to live as long as the process
+    let mut _audio_stream = None;
+    let mut _midi_connection = None;
+    let mut playback = None;
+    let source = if let Ok(path) = env::var("REPLAY_FRAMES") {
+        eprintln!("Replaying frames from {}", path);
+        Source::Replay(frames::Replay::load(&path)?)
+    } else if let Ok(path) = env::var("MIDI_FILE") {
+        eprintln!("Playing MIDI file {}", path);
+        let keyboard = Arc::new(Mutex::new(midi::Keyboard::new()));
+        playback = Some(playback::start(
+            playback::load(&path)?,
+            Arc::clone(&keyboard),
+        ));
+        Source::Midi(keyboard)
+    } else if let Ok(port) = env::var("MIDI_INPUT") {
+        eprintln!("Using MIDI input");
+        let keyboard = Arc::new(Mutex::new(midi::Keyboard::new()));
+        _midi_connection = Some(midi::connect(&port, Arc::clone(&keyboard))?);
+        Source::Midi(keyboard)
+    } else {
+        let (stream, input) = audio::start()?;
+        _audio_stream = Some(stream);
+        Source::Audio(input)
+    };
 
-    stream_config.buffer_size = cpal::BufferSize::Fixed(1024);
+    let mut pipeline = Pipeline::new(source);
+    pipeline.midi_output = match env::var("MIDI_OUTPUT") {
+        Ok(target) => Some(midi_output::open(&target)?),
+        Err(_) => None,
+    };
 
-    let tx_audio = tx.clone();
+    pipeline.lesson = match env::var("LESSON_FILE") {
+        Ok(path) => {
+            eprintln!("Loaded lesson {}", path);
+            Some(lesson::load(&path)?)
+        }
+        Err(_) => None,
+    };
 
-    let stream = device.build_input_stream(
-        &stream_config,
-        move |samples: &[f32], _: &cpal::InputCallbackInfo| {
-            if let Ok(mut buffer) = producer_buffer.lock() {
-                buffer.push_iter_overwrite(&mut samples.iter().copied());
-                if tx_audio.send(Ping::Audio).is_err() {
-                    panic!("Failed to send timeout ping!");
-                }
-            }
-        },
-        |err| panic!("an error occurred on stream: {}", err),
-        None,
-    )?;
+    pipeline.session_log = env::var("SESSION_LOG")
+        .ok()
+        .map(|target| session::SessionLog::new(&target));
 
-    stream.play()?;
+    pipeline.recorder = match env::var("RECORD_FRAMES") {
+        Ok(path) => {
+            eprintln!("Recording frames to {}", path);
+            Some(frames::Recorder::create(&path)?)
+        }
+        Err(_) => None,
+    };
 
-    thread::spawn(move || {
-        let mut last_ping: Option<Ping> = None;
-        loop {
-            match rx.recv() {
-                Ok(Ping::Audio) => {
-                    last_ping = Some(Ping::Audio);
-                }
-                Ok(Ping::Timeout) => match last_ping {
-                    Some(Ping::Timeout) => {
-                        panic!("Two consecutive timeouts! Exiting");
-                    }
-                    Some(Ping::Audio) => {
-                        last_ping = Some(Ping::Timeout);
-                    }
-                    _none => {
-                        panic!("Received timeout ping before audio. Exiting");
-                    }
-                },
-                Err(err) => {
-                    eprintln!("error reading timeout consumer: {}", err);
-                }
-            }
+    pipeline.telemetry = match env::var("TELEMETRY_INTERVAL") {
+        Ok(ms) => {
+            let ms: u64 = ms.parse()?;
+            eprintln!("Sending telemetry every {}ms", ms);
+            Some(telemetry::Telemetry::new(Duration::from_millis(ms)))
         }
-    });
+        Err(_) => None,
+    };
 
-    thread::spawn(move || loop {
-        thread::sleep(Duration::from_millis(500));
-        if tx.send(Ping::Timeout).is_err() {
-            panic!("Failed to send timeout ping!");
+    let preview = match env::var("PREVIEW") {
+        Ok(target) => {
+            let fps = match env::var("PREVIEW_FPS") {
+                Ok(fps) => fps
+                    .parse::<f32>()
+                    .ok()
+                    .filter(|fps| fps.is_finite() && *fps > 0.0)
+                    .ok_or_else(|| format!("PREVIEW_FPS must be a positive number, got {}", fps))?,
+                Err(_) => DEFAULT_PREVIEW_FPS,
+            };
+            eprintln!("Previewing frames to {} at {} fps", target, fps);
+            let levels = env::var("PREVIEW_LEVELS").is_ok();
+            Some((preview::Sink::open(&target)?, fps, levels))
         }
-    });
+        Err(_) => None,
+    };
 
     let (tx_stdin, rx_exit) = mpsc::channel();
     // let tx_stdout = tx_stdin.clone();
+    // With a config file the lights can run on their own, e.g. as a service
+    // with nothing on stdin, so closing stdin doesn't stop them. This keeps
+    // the exit channel open once the stdin thread has finished.
+    let standalone = config_path.is_some();
+    let _tx_exit = tx_stdin.clone();
+    wait_for_shutdown_signals(signals, tx_stdin.clone());
+    // commands are carried out by the display loop, which owns the pipeline
+    let (tx_command, rx_command) = mpsc::channel();
 
     thread::spawn(move || {
         let stdin = io::stdin();
         let mut reader = BufReader::new(stdin.lock());
-        let mut line = String::new();
 
         loop {
-            line.clear();
-            match reader.read_line(&mut line) {
-                Ok(0) => {
+            // a bad message is reported and the previous config stays active
+            let request = match protocol::read_request(&mut reader, format) {
+                Ok(Some(request)) => request,
+                Ok(None) if standalone => {
+                    eprintln!("Child: stdin closed, carrying on with the config file");
+                    break;
+                }
+                Ok(None) => {
                     // EOF reached - stdin closed
                     eprintln!("Child: stdin closed by parent");
                     let _ = tx_stdin.send(());
                     break;
                 }
-                Ok(_) => {
-                    // Successfully read a line
-                    let c: display::DisplayConfig =
-                        DisplayConfig::decode(&line).expect("Failed to decode json");
-                    if let Ok(mut wrapper) = display_config_write.lock() {
-                        wrapper.config = c;
-                    }
-                    // io::stdout().flush().unwrap();
-                }
                 Err(e) => {
                     // Error reading from stdin
                     eprintln!("Child: error reading stdin: {}", e);
                     let _ = tx_stdin.send(());
                     break;
                 }
+            };
+            match request {
+                Ok(protocol::Request::Config(patch)) => {
+                    if let Ok(mut wrapper) = display_config_write.lock() {
+                        match protocol::apply(&wrapper.config, &patch) {
+                            Ok(config) => wrapper.config = config,
+                            Err(error) => {
+                                eprintln!("Child: rejected config: {}", error.message);
+                                error.send();
+                            }
+                        }
+                    }
+                }
+                Ok(protocol::Request::Playback(control)) => match &playback {
+                    Some(playback) => {
+                        let _ = playback.send(control);
+                    }
+                    None => eprintln!("Child: no MIDI file is playing"),
+                },
+                Ok(protocol::Request::Command { id, command }) => {
+                    let _ = tx_command.send((id, command));
+                }
+                Err(error) => {
+                    eprintln!("Child: rejected message: {}", error.message);
+                    error.send();
+                }
             }
         }
     });
@@ -176,41 +259,34 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     // wait for buffer to fill
     thread::sleep(Duration::from_millis(100));
 
-    thread::spawn(move || {
-        let mut peak_magnitudes = vec![0.0; num_bins];
-        let sample_rate = stream_config.sample_rate.0 as u32;
-        let mut samples = [0.0f32; SAMPLE_SIZE];
-        let mut bins = vec![0.0; num_bins];
-        let mut display = display_impl();
-        loop {
-            thread::sleep(Duration::from_millis(4));
-
-            if let Ok(buffer) = consumer_buffer.lock() {
-                let _samples_read = buffer.peek_slice(&mut samples);
-            }
-            let hann_window = hann_window(&samples);
-            if let Ok(wrapper) = display_config_read.lock() {
-                let fncs: Box<spectrum_analyzer::scaling::SpectrumScalingFunction> =
-                    if wrapper.config.scale {
-                        Box::new(&divide_by_N_sqrt)
-                    } else {
-                        combined(&[
-                            &scale_20_times_log10,
-                            // &divide_by_N_sqrt,
-                            &scale_to_zero_to_one,
-                        ])
-                    };
-                let spectrum = samples_fft_to_spectrum(
-                    &hann_window,
-                    sample_rate,
-                    FrequencyLimit::Range(piano::MIN_FREQUENCY, piano::MAX_FREQUENCY),
-                    Some(&fncs),
+    let (tx_shutdown, rx_shutdown) = mpsc::channel();
+    let display_thread = thread::spawn(move || {
+        let display = display_impl(&layout);
+        let (mut pipeline, result) = match preview {
+            Some((sink, fps, levels)) => {
+                let display = preview::Preview::new(display, sink, fps, levels, layout.num_leds);
+                draw(
+                    display,
+                    pipeline,
+                    rx_command,
+                    rx_shutdown,
+                    display_config_read,
                 )
-                .unwrap();
-
-                piano::bin_magnitudes(&mut bins, spectrum, num_bins, &wrapper.config);
-                display.visualize_bins(&bins, &mut peak_magnitudes, &wrapper.config);
             }
+            None => draw(
+                display,
+                pipeline,
+                rx_command,
+                rx_shutdown,
+                display_config_read,
+            ),
+        };
+        pipeline.finish();
+        // exit with an error so a service manager restarts the lights
+        if let Err(err) = result {
+            eprintln!("Child: {}", err);
+            display_impl(&layout).reset();
+            process::exit(1);
         }
     });
 
@@ -224,22 +300,90 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     }
 
     eprintln!("Child: Exiting gracefully");
-    let mut display = display_impl();
+    // let the last frame finish and the outputs save what they have
+    let _ = tx_shutdown.send(());
+    let _ = display_thread.join();
+    let mut display = display_impl(&layout);
     display.reset();
     process::exit(0);
 }
 
+// SIGTERM, from systemd stopping the service, and SIGINT are blocked in
+// every thread and waited for on one, so they end the process the same way
+// closing stdin does: the last frame drawn, the outputs saved and the strip
+// switched off.
+fn block_shutdown_signals() -> libc::sigset_t {
+    // SAFETY: the set is initialised by sigemptyset before it is used, and
+    // changing the signal mask has no other effect on this thread
+    unsafe {
+        let mut signals: libc::sigset_t = std::mem::zeroed();
+        libc::sigemptyset(&mut signals);
+        libc::sigaddset(&mut signals, libc::SIGTERM);
+        libc::sigaddset(&mut signals, libc::SIGINT);
+        libc::pthread_sigmask(libc::SIG_BLOCK, &signals, std::ptr::null_mut());
+        signals
+    }
+}
+
+fn wait_for_shutdown_signals(signals: libc::sigset_t, exit: mpsc::Sender<()>) {
+    thread::spawn(move || {
+        let mut signal = 0;
+        // SAFETY: `signals` is a valid set and `signal` outlives the call
+        if unsafe { libc::sigwait(&signals, &mut signal) } == 0 {
+            eprintln!("Child: received signal {}", signal);
+            let _ = exit.send(());
+        }
+    });
+}
+
+// why a config from `source` wasn't used
+fn config_error(source: &str, err: protocol::Error) -> String {
+    match err.field {
+        Some(field) => format!("{} has an error at {}: {}", source, field, err.message),
+        None => format!("{} has an error: {}", source, err.message),
+    }
+}
+
+// Run commands and draw frames until told to shut down or the source
+// fails, then hand the pipeline back.
+fn draw<D: Display>(
+    mut display: D,
+    mut pipeline: Pipeline,
+    commands: mpsc::Receiver<(Option<serde_json::Value>, control::Command)>,
+    shutdown: mpsc::Receiver<()>,
+    display_config: Arc<Mutex<ConfigWrapper>>,
+) -> (Pipeline, Result<(), String>) {
+    loop {
+        thread::sleep(Duration::from_millis(4));
+        if shutdown.try_recv().is_ok() {
+            return (pipeline, Ok(()));
+        }
+
+        if let Ok(mut wrapper) = display_config.lock() {
+            for (id, command) in commands.try_iter() {
+                match pipeline.command(&command, &wrapper.config) {
+                    Ok(reply) => protocol::reply(id.as_ref(), &reply),
+                    Err(message) => protocol::Error::failed(id, message).send(),
+                }
+            }
+            if let Err(err) = pipeline.frame(&mut wrapper.config, &mut display) {
+                return (pipeline, Err(err));
+            }
+        }
+    }
+}
+
 #[cfg(feature = "leds")]
-fn display_impl() -> impl display::Display {
-    leds::LEDs::new()
+fn display_impl(layout: &Layout) -> impl display::Display {
+    leds::leds::LEDs::new(layout)
 }
 
 #[cfg(feature = "terminal")]
-fn display_impl() -> impl display::Display {
-    terminal::Terminal::new()
+fn display_impl(_layout: &Layout) -> impl display::Display {
+    leds::terminal::Terminal::new()
 }
 
 #[cfg(feature = "no-display")]
-fn display_impl() -> impl display::Display {
-    null::Null::new()
+fn display_impl(_layout: &Layout) -> impl display::Display {
+    leds::null::Null::new()
 }
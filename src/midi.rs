@@ -0,0 +1,157 @@
+use std::error::Error;
+use std::sync::{Arc, Mutex};
+
+use midir::os::unix::VirtualInput;
+use midir::{MidiInput, MidiInputConnection};
+
+use crate::{piano, report};
+
+const CLIENT_NAME: &str = "blinken-lights";
+/// MIDI_INPUT value that creates a virtual port instead of connecting to a
+/// device
+pub const VIRTUAL_PORT: &str = "virtual";
+
+// MIDI note number of key 1 (A0)
+const LOWEST_NOTE: u8 = 21;
+const SUSTAIN_PEDAL: u8 = 64;
+
+/// The state of the keys as reported by a MIDI instrument, turned into the
+/// same per-key levels the audio analysis produces.
+pub struct Keyboard {
+    velocities: [u8; 128],
+    held: [bool; 128],
+    sustain: bool,
+}
+
+impl Keyboard {
+    /// A keyboard with nothing held.
+    pub fn new() -> Self {
+        Keyboard {
+            velocities: [0; 128],
+            held: [false; 128],
+            sustain: false,
+        }
+    }
+
+    /// Let go of every key and the sustain pedal.
+    pub fn reset(&mut self) {
+        *self = Keyboard::new();
+    }
+
+    /// Update the keys from a MIDI message. Anything but note on/off and
+    /// the sustain pedal is ignored.
+    pub fn handle(&mut self, message: &[u8]) {
+        match *message {
+            [status, note, velocity] if status & 0xF0 == 0x90 && velocity > 0 => {
+                let note = (note & 0x7F) as usize;
+                self.velocities[note] = velocity;
+                self.held[note] = true;
+            }
+            [status, note, _] if status & 0xF0 == 0x80 || status & 0xF0 == 0x90 => {
+                let note = (note & 0x7F) as usize;
+                self.held[note] = false;
+                if !self.sustain {
+                    self.velocities[note] = 0;
+                }
+            }
+            [status, SUSTAIN_PEDAL, value] if status & 0xF0 == 0xB0 => {
+                self.sustain = value >= 64;
+                if !self.sustain {
+                    // let go of everything that was only ringing on the pedal
+                    for (velocity, held) in self.velocities.iter_mut().zip(self.held) {
+                        if !held {
+                            *velocity = 0;
+                        }
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Levels for each bin, by velocity. Released notes drop to zero and
+    /// fade out on the display like a decaying audio peak, unless the
+    /// sustain pedal is holding them.
+    pub fn levels(&self, bins: &mut [f32]) {
+        for (i, val) in bins.iter_mut().enumerate() {
+            let note = piano::bin_key_number(i) + LOWEST_NOTE as usize - 1;
+            *val = self.velocities.get(note).map_or(0.0, |&v| v as f32 / 127.0);
+        }
+    }
+}
+
+impl Default for Keyboard {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Listen for MIDI on the first port whose name contains `port`, or on a new
+/// virtual port if `port` is "virtual".
+pub fn connect(
+    port: &str,
+    keyboard: Arc<Mutex<Keyboard>>,
+) -> Result<MidiInputConnection<()>, Box<dyn Error>> {
+    let input = MidiInput::new(CLIENT_NAME)?;
+    let callback = move |_stamp: u64, message: &[u8], _: &mut ()| {
+        if let Ok(mut keyboard) = keyboard.lock() {
+            keyboard.handle(message);
+        }
+    };
+
+    if port == VIRTUAL_PORT {
+        report::log(&format!(
+            "Listening for MIDI on virtual port {}",
+            CLIENT_NAME
+        ));
+        return Ok(input.create_virtual(CLIENT_NAME, callback, ())?);
+    }
+
+    let found = input
+        .ports()
+        .into_iter()
+        .find(|p| input.port_name(p).is_ok_and(|name| name.contains(port)))
+        .ok_or_else(|| format!("no MIDI input port matching {:?}", port))?;
+    report::log(&format!(
+        "Listening for MIDI on {}",
+        input.port_name(&found)?
+    ));
+    Ok(input.connect(&found, CLIENT_NAME, callback, ())?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn level(keyboard: &Keyboard, key_number: usize) -> f32 {
+        let mut bins = vec![0.0; piano::num_keys()];
+        keyboard.levels(&mut bins);
+        bins[key_number - piano::bin_key_number(0)]
+    }
+
+    #[test]
+    fn test_note_on_off() {
+        let mut keyboard = Keyboard::new();
+        // middle C, key 40
+        keyboard.handle(&[0x90, 60, 127]);
+        assert_eq!(level(&keyboard, 40), 1.0);
+        // note on with velocity 0 is a note off
+        keyboard.handle(&[0x91, 60, 0]);
+        assert_eq!(level(&keyboard, 40), 0.0);
+    }
+
+    #[test]
+    fn test_sustain_pedal() {
+        let mut keyboard = Keyboard::new();
+        keyboard.handle(&[0xB0, SUSTAIN_PEDAL, 127]);
+        keyboard.handle(&[0x90, 60, 64]);
+        keyboard.handle(&[0x90, 64, 64]);
+        keyboard.handle(&[0x80, 60, 0]);
+        assert!(level(&keyboard, 40) > 0.0);
+
+        keyboard.handle(&[0xB0, SUSTAIN_PEDAL, 0]);
+        assert_eq!(level(&keyboard, 40), 0.0);
+        // still held down
+        assert!(level(&keyboard, 44) > 0.0);
+    }
+}
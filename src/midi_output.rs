@@ -0,0 +1,335 @@
+use std::error::Error;
+use std::time::{Duration, Instant};
+
+use midir::os::unix::VirtualOutput;
+use midir::{MidiOutput, MidiOutputConnection};
+use midly::num::{u4, u7, u15, u24, u28};
+use midly::{Format, Header, MetaMessage, MidiMessage, Smf, Timing, TrackEvent, TrackEventKind};
+
+use crate::analysis::Input;
+use crate::midi::VIRTUAL_PORT;
+use crate::{piano, report};
+
+const CLIENT_NAME: &str = "blinken-lights";
+// a key turns on above ON_LEVEL and off again below OFF_LEVEL, so a note
+// hovering around one threshold doesn't retrigger
+const ON_LEVEL: f32 = 0.5;
+const OFF_LEVEL: f32 = 0.25;
+// MIDI levels are velocities, so a key is on for as long as it has one
+const MIDI_LEVEL: f32 = f32::MIN_POSITIVE;
+// with 500 ticks per beat at 120 BPM one tick is a millisecond
+const TICKS_PER_BEAT: u16 = 500;
+const TEMPO: u32 = 500_000;
+// how often a recording is rewritten to disk
+const SAVE_EVERY: Duration = Duration::from_secs(1);
+
+/// A key going down or coming up.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Note {
+    /// piano key number, A0 is 1
+    pub key_number: usize,
+    /// 0 for note off
+    pub velocity: u8,
+}
+
+impl Note {
+    fn message(&self) -> [u8; 3] {
+        let note = (self.key_number + 20) as u8;
+        if self.velocity > 0 {
+            [0x90, note, self.velocity]
+        } else {
+            [0x80, note, 0]
+        }
+    }
+}
+
+/// Turns per-key levels into note on and off events.
+pub struct NoteDetector {
+    on: Vec<bool>,
+}
+
+impl NoteDetector {
+    /// A detector with no notes on.
+    pub fn new() -> Self {
+        NoteDetector { on: Vec::new() }
+    }
+
+    /// Quiet audio turns every note off, see `analysis::QUIET_RMS`, and
+    /// MIDI levels count as on however softly the key was played.
+    pub fn update(&mut self, bins: &[f32], input: Input) -> Vec<Note> {
+        self.on.resize(bins.len(), false);
+        let (on_level, off_level) = match input {
+            Input::Midi => (MIDI_LEVEL, MIDI_LEVEL),
+            _ => (ON_LEVEL, OFF_LEVEL),
+        };
+        let mut notes = Vec::new();
+        for (i, &level) in bins.iter().enumerate() {
+            let level = if input.quiet() { 0.0 } else { level };
+            let key_number = piano::bin_key_number(i);
+            if !self.on[i] && level >= on_level {
+                self.on[i] = true;
+                notes.push(Note {
+                    key_number,
+                    velocity: velocity(level),
+                });
+            } else if self.on[i] && level < off_level {
+                self.on[i] = false;
+                notes.push(Note {
+                    key_number,
+                    velocity: 0,
+                });
+            }
+        }
+        notes
+    }
+
+    /// the keys (1-88) that are currently on
+    pub fn held(&self) -> Vec<usize> {
+        (self.on.iter().enumerate())
+            .filter(|&(_, &on)| on)
+            .map(|(i, _)| piano::bin_key_number(i))
+            .collect()
+    }
+}
+
+impl Default for NoteDetector {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn velocity(level: f32) -> u8 {
+    (level.clamp(0.0, 1.0) * 126.0).round() as u8 + 1
+}
+
+/// Where detected notes are sent: a MIDI port or a Standard MIDI File.
+pub enum Output {
+    /// a connected MIDI port
+    Port(MidiOutputConnection),
+    /// a file written as notes arrive
+    File(Recording),
+}
+
+/// Open `target`, which is "virtual" for a new virtual port, a path ending in
+/// `.mid` to record to a file, or part of the name of an existing port.
+pub fn open(target: &str) -> Result<Output, Box<dyn Error>> {
+    if target.ends_with(".mid") {
+        report::log(&format!("Recording detected notes to {}", target));
+        return Ok(Output::File(Recording::new(target)));
+    }
+    let output = MidiOutput::new(CLIENT_NAME)?;
+    if target == VIRTUAL_PORT {
+        report::log(&format!(
+            "Sending detected notes on virtual port {}",
+            CLIENT_NAME
+        ));
+        return Ok(Output::Port(output.create_virtual(CLIENT_NAME)?));
+    }
+    let port = output
+        .ports()
+        .into_iter()
+        .find(|p| output.port_name(p).is_ok_and(|name| name.contains(target)))
+        .ok_or_else(|| format!("no MIDI output port matching {:?}", target))?;
+    report::log(&format!(
+        "Sending detected notes to {}",
+        output.port_name(&port)?
+    ));
+    Ok(Output::Port(output.connect(&port, CLIENT_NAME)?))
+}
+
+impl Output {
+    /// write out anything not saved yet, before exiting
+    pub fn flush(&mut self) {
+        if let Output::File(recording) = self {
+            recording.save();
+        }
+    }
+
+    /// Send `notes` on, in order. Failures are logged rather than stopping
+    /// the lights.
+    pub fn send(&mut self, notes: &[Note]) {
+        match self {
+            Output::Port(connection) => {
+                for note in notes {
+                    if let Err(err) = connection.send(&note.message()) {
+                        report::log(&format!("failed to send MIDI: {}", err));
+                    }
+                }
+            }
+            Output::File(recording) => recording.record(notes),
+        }
+    }
+}
+
+/// Detected notes saved as a Standard MIDI File, rewritten every second
+/// and when flushed.
+pub struct Recording {
+    path: String,
+    started: Instant,
+    last_saved: Instant,
+    // absolute tick and message
+    events: Vec<(u32, [u8; 3])>,
+    unsaved: bool,
+}
+
+impl Recording {
+    fn new(path: &str) -> Self {
+        Recording {
+            path: path.to_string(),
+            started: Instant::now(),
+            last_saved: Instant::now(),
+            events: Vec::new(),
+            unsaved: false,
+        }
+    }
+
+    fn record(&mut self, notes: &[Note]) {
+        let tick = self.started.elapsed().as_millis() as u32;
+        for note in notes {
+            self.events.push((tick, note.message()));
+            self.unsaved = true;
+        }
+        // the whole file is rewritten so it's always complete if we're
+        // killed
+        if self.last_saved.elapsed() >= SAVE_EVERY {
+            self.save();
+        }
+    }
+
+    fn save(&mut self) {
+        if !self.unsaved {
+            return;
+        }
+        self.last_saved = Instant::now();
+        self.unsaved = false;
+        if let Err(err) = self.smf().save(&self.path) {
+            report::log(&format!("failed to save {}: {}", self.path, err));
+        }
+    }
+
+    fn smf(&self) -> Smf<'static> {
+        let mut track = vec![TrackEvent {
+            delta: u28::new(0),
+            kind: TrackEventKind::Meta(MetaMessage::Tempo(u24::new(TEMPO))),
+        }];
+        let mut last_tick = 0;
+        for &(tick, [status, key, vel]) in &self.events {
+            let key = u7::new(key);
+            let vel = u7::new(vel);
+            let message = if status & 0xF0 == 0x90 {
+                MidiMessage::NoteOn { key, vel }
+            } else {
+                MidiMessage::NoteOff { key, vel }
+            };
+            track.push(TrackEvent {
+                delta: u28::new(tick - last_tick),
+                kind: TrackEventKind::Midi {
+                    channel: u4::new(0),
+                    message,
+                },
+            });
+            last_tick = tick;
+        }
+        track.push(TrackEvent {
+            delta: u28::new(0),
+            kind: TrackEventKind::Meta(MetaMessage::EndOfTrack),
+        });
+        Smf {
+            header: Header::new(
+                Format::SingleTrack,
+                Timing::Metrical(u15::new(TICKS_PER_BEAT)),
+            ),
+            tracks: vec![track],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOUD: Input = Input::Audio { rms: 0.1 };
+    const QUIET: Input = Input::Audio { rms: 0.0 };
+
+    #[test]
+    fn test_detector_hysteresis() {
+        let mut detector = NoteDetector::new();
+        let mut bins = vec![0.0; piano::num_keys()];
+        let key_number = piano::bin_key_number(10);
+
+        bins[10] = 1.0;
+        assert_eq!(
+            detector.update(&bins, LOUD),
+            vec![Note {
+                key_number,
+                velocity: 127
+            }]
+        );
+        // between the thresholds nothing changes
+        bins[10] = 0.3;
+        assert_eq!(detector.update(&bins, LOUD), vec![]);
+        bins[10] = 0.1;
+        assert_eq!(
+            detector.update(&bins, LOUD),
+            vec![Note {
+                key_number,
+                velocity: 0
+            }]
+        );
+    }
+
+    #[test]
+    fn test_detector_ignores_quiet_input() {
+        let mut detector = NoteDetector::new();
+        // normalised levels from room noise look just like a note
+        let mut bins = vec![0.0; piano::num_keys()];
+        bins[10] = 1.0;
+        assert_eq!(detector.update(&bins, QUIET), vec![]);
+        assert!(detector.held().is_empty());
+
+        detector.update(&bins, LOUD);
+        assert_eq!(detector.held(), vec![piano::bin_key_number(10)]);
+        assert_eq!(detector.update(&bins, QUIET)[0].velocity, 0);
+        assert!(detector.held().is_empty());
+    }
+
+    #[test]
+    fn test_detector_takes_soft_midi_notes() {
+        let mut detector = NoteDetector::new();
+        let mut bins = vec![0.0; piano::num_keys()];
+        bins[10] = 20.0 / 127.0;
+        assert_eq!(detector.update(&bins, LOUD), vec![]);
+        assert_eq!(detector.update(&bins, Input::Midi).len(), 1);
+        assert_eq!(detector.held(), vec![piano::bin_key_number(10)]);
+
+        bins[10] = 0.0;
+        assert_eq!(detector.update(&bins, Input::Midi)[0].velocity, 0);
+    }
+
+    #[test]
+    fn test_recording_flushes() {
+        let path = std::env::temp_dir().join(format!("leds-flush-{}.mid", std::process::id()));
+        let mut output = Output::File(Recording::new(path.to_str().unwrap()));
+        output.send(&[Note {
+            key_number: 40,
+            velocity: 100,
+        }]);
+        // too soon for the periodic save
+        assert!(!path.exists());
+        output.flush();
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(Smf::parse(&bytes).unwrap().tracks[0].len(), 3);
+    }
+
+    #[test]
+    fn test_recording_round_trips() {
+        let mut recording = Recording::new("unused.mid");
+        recording.events = vec![(0, [0x90, 60, 100]), (250, [0x80, 60, 0])];
+        let mut bytes = Vec::new();
+        recording.smf().write_std(&mut bytes).unwrap();
+        let smf = Smf::parse(&bytes).unwrap();
+        assert_eq!(smf.tracks[0].len(), 4);
+        assert_eq!(smf.tracks[0][2].delta.as_int(), 250);
+    }
+}
@@ -0,0 +1,279 @@
+use std::fmt;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::chroma::Chroma;
+use crate::piano::{self, NOTE_NAMES};
+use crate::report;
+
+// how far back the pitch-class history reaches. older energy decays away
+// exponentially
+const WINDOW: Duration = Duration::from_secs(30);
+// how often the key is re-estimated
+const ESTIMATE_EVERY: Duration = Duration::from_secs(1);
+// seconds of full-level playing needed before estimating
+const MIN_ENERGY: f32 = 2.0;
+// minimum correlation with a key profile to name a key
+const MIN_CORRELATION: f32 = 0.5;
+
+// Krumhansl-Kessler key profiles, indexed by semitones above the tonic
+const MAJOR_PROFILE: [f32; 12] = [
+    6.35, 2.23, 3.48, 2.33, 4.38, 4.09, 2.52, 5.19, 2.39, 3.66, 2.29, 2.88,
+];
+const MINOR_PROFILE: [f32; 12] = [
+    6.33, 2.68, 3.52, 5.38, 2.60, 3.53, 2.54, 4.75, 3.98, 2.69, 3.34, 3.17,
+];
+
+/// major or minor
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum KeyMode {
+    /// a major key
+    Major,
+    /// a natural minor key
+    Minor,
+}
+
+impl KeyMode {
+    fn profile(&self) -> &'static [f32; 12] {
+        match self {
+            KeyMode::Major => &MAJOR_PROFILE,
+            KeyMode::Minor => &MINOR_PROFILE,
+        }
+    }
+
+    // semitones above the tonic, natural minor for minor keys
+    fn scale(&self) -> &'static [usize] {
+        match self {
+            KeyMode::Major => &[0, 2, 4, 5, 7, 9, 11],
+            KeyMode::Minor => &[0, 2, 3, 5, 7, 8, 10],
+        }
+    }
+}
+
+/// A key the music is in, e.g. D minor.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MusicalKey {
+    /// pitch class, see `piano::key_number_to_index`
+    pub tonic: usize,
+    /// whether it's major or minor
+    pub mode: KeyMode,
+}
+
+impl MusicalKey {
+    /// Is `pitch_class` one of the notes of the key's scale?
+    pub fn contains(&self, pitch_class: usize) -> bool {
+        self.mode
+            .scale()
+            .contains(&((pitch_class + 12 - self.tonic) % 12))
+    }
+
+    /// Is the piano key `key_number` in the key's scale?
+    pub fn contains_key_number(&self, key_number: usize) -> bool {
+        self.contains(piano::key_number_to_index(key_number))
+    }
+}
+
+impl fmt::Display for MusicalKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mode = match self.mode {
+            KeyMode::Major => "major",
+            KeyMode::Minor => "minor",
+        };
+        write!(f, "{} {}", NOTE_NAMES[self.tonic], mode)
+    }
+}
+
+/// The key whose profile best correlates with the pitch-class energy.
+pub fn estimate(energy: &Chroma) -> Option<MusicalKey> {
+    if energy.iter().sum::<f32>() < MIN_ENERGY {
+        return None;
+    }
+    let mut best: Option<(MusicalKey, f32)> = None;
+    for mode in [KeyMode::Major, KeyMode::Minor] {
+        let profile = mode.profile();
+        for tonic in 0..12 {
+            let rotated: Vec<f32> = (0..12).map(|p| profile[(p + 12 - tonic) % 12]).collect();
+            let r = correlation(energy, &rotated);
+            if best.is_none_or(|(_, best_r)| r > best_r) {
+                best = Some((MusicalKey { tonic, mode }, r));
+            }
+        }
+    }
+    best.filter(|(_, r)| *r >= MIN_CORRELATION)
+        .map(|(key, _)| key)
+}
+
+fn correlation(a: &[f32], b: &[f32]) -> f32 {
+    let n = a.len() as f32;
+    let mean_a = a.iter().sum::<f32>() / n;
+    let mean_b = b.iter().sum::<f32>() / n;
+    let mut cov = 0.0;
+    let mut var_a = 0.0;
+    let mut var_b = 0.0;
+    for (x, y) in a.iter().zip(b) {
+        cov += (x - mean_a) * (y - mean_b);
+        var_a += (x - mean_a).powi(2);
+        var_b += (y - mean_b).powi(2);
+    }
+    if var_a == 0.0 || var_b == 0.0 {
+        return 0.0;
+    }
+    cov / (var_a * var_b).sqrt()
+}
+
+#[derive(Serialize)]
+struct Report {
+    key: Option<KeyReport>,
+}
+
+#[derive(Serialize)]
+struct KeyReport {
+    name: String,
+    tonic: &'static str,
+    mode: KeyMode,
+}
+
+/// Accumulates pitch-class energy over a sliding window and reports changes
+/// of key on stdout.
+pub struct KeyEstimator {
+    energy: Chroma,
+    since_estimate: Duration,
+    current: Option<MusicalKey>,
+}
+
+impl KeyEstimator {
+    /// An estimator that hasn't heard anything yet.
+    pub fn new() -> Self {
+        KeyEstimator {
+            energy: [0.0; 12],
+            since_estimate: Duration::ZERO,
+            current: None,
+        }
+    }
+
+    /// Take the next frame's chroma, `elapsed` after the last one. A frame
+    /// where the input is `quiet` (see `analysis::Input::quiet`) adds
+    /// nothing, its chroma is only normalised noise, though older energy
+    /// still decays. Returns the current estimate, if there's been enough
+    /// to go on.
+    pub fn update(
+        &mut self,
+        chroma: &Chroma,
+        elapsed: Duration,
+        quiet: bool,
+    ) -> Option<MusicalKey> {
+        let dt = elapsed.as_secs_f32();
+        let decay = (-dt / WINDOW.as_secs_f32()).exp();
+        let added = if quiet { 0.0 } else { dt };
+        for (energy, level) in self.energy.iter_mut().zip(chroma) {
+            *energy = *energy * decay + level * added;
+        }
+
+        self.since_estimate += elapsed;
+        if self.since_estimate >= ESTIMATE_EVERY {
+            self.since_estimate = Duration::ZERO;
+            let key = estimate(&self.energy);
+            if key != self.current {
+                self.current = key;
+                self.report();
+            }
+        }
+        self.current
+    }
+
+    fn report(&self) {
+        let message = Report {
+            key: self.current.map(|key| KeyReport {
+                name: key.to_string(),
+                tonic: NOTE_NAMES[key.tonic],
+                mode: key.mode,
+            }),
+        };
+        report::send(&message);
+    }
+}
+
+impl Default for KeyEstimator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_estimate() {
+        // a C major scale with weight on the triad
+        let mut energy = [0.0; 12];
+        for (p, level) in [
+            (3, 3.0),
+            (5, 1.0),
+            (7, 2.0),
+            (8, 1.0),
+            (10, 2.5),
+            (0, 1.0),
+            (2, 1.0),
+        ] {
+            energy[p] = level;
+        }
+        let key = estimate(&energy).unwrap();
+        assert_eq!(key.to_string(), "C major");
+        assert!(key.contains(2));
+        assert!(!key.contains(4));
+
+        assert_eq!(estimate(&[0.0; 12]), None);
+    }
+
+    fn a_minor() -> Chroma {
+        let mut a_minor = [0.0; 12];
+        for (p, level) in [
+            (0, 1.0),
+            (3, 0.6),
+            (7, 0.8),
+            (2, 0.3),
+            (5, 0.3),
+            (8, 0.3),
+            (10, 0.3),
+        ] {
+            a_minor[p] = level;
+        }
+        a_minor
+    }
+
+    #[test]
+    fn test_estimator_accumulates() {
+        let mut estimator = KeyEstimator::new();
+        let mut key = None;
+        for _ in 0..100 {
+            key = estimator.update(&a_minor(), Duration::from_millis(50), false);
+        }
+        assert_eq!(key.unwrap().to_string(), "A minor");
+    }
+
+    #[test]
+    fn test_estimator_ignores_quiet_input() {
+        let mut estimator = KeyEstimator::new();
+        let frame = Duration::from_millis(50);
+        // normalised room noise can look like anything, here C# major
+        let mut noise = [0.0; 12];
+        for p in [4, 6, 8, 9, 11, 1, 3] {
+            noise[p] = 1.0;
+        }
+        for _ in 0..100 {
+            assert_eq!(estimator.update(&noise, frame, true), None);
+        }
+
+        let mut key = None;
+        for _ in 0..100 {
+            key = estimator.update(&a_minor(), frame, false);
+        }
+        for _ in 0..100 {
+            key = estimator.update(&noise, frame, true);
+        }
+        assert_eq!(key.unwrap().to_string(), "A minor");
+    }
+}
@@ -1,19 +1,26 @@
-#![allow(dead_code)]
-
 use crate::display;
 
+/// A display that shows nothing, for running without any lights.
 pub struct Null {}
 
 impl Null {
+    /// A new display that shows nothing.
     pub fn new() -> Self {
         Null {}
     }
 }
 
+impl Default for Null {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl display::Display for Null {
     fn visualize_bins(
         &mut self,
         _bins: &[f32],
+        _colours: &[Option<display::KeyColour>],
         _peak_magnitudes: &mut Vec<f32>,
         _config: &display::DisplayConfig,
     ) {
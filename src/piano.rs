@@ -1,32 +1,88 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
 use spectrum_analyzer::FrequencySpectrum;
 
 use crate::display::DisplayConfig;
 
+/// keys on a full size piano
 pub const NUM_KEYS: usize = 88;
+/// the highest frequency in Hz that's analysed
 pub const MAX_FREQUENCY: f32 = 4200.0;
+/// the lowest frequency in Hz that's analysed, which sets the lowest key
+/// shown
 pub const MIN_FREQUENCY: f32 = 120.0;
 
+/// indexed by `key_number_to_index`
+pub const NOTE_NAMES: [&str; 12] = [
+    "A", "A#", "B", "C", "C#", "D", "D#", "E", "F", "F#", "G", "G#",
+];
+
 // 1-88
 type KeyIndex = usize;
 type BinIndex = usize;
 
-#[derive(Debug, PartialEq)]
+/// whether a piano key is white or black
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub enum KeyColour {
+    /// a natural
     White,
+    /// a sharp or flat
     Black,
 }
 
+/// how the energy in a spectral bin is assigned to the keys either side of
+/// its frequency
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, JsonSchema)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum Weighting {
+    /// all of the energy goes to the closest key
+    Nearest,
+    /// both neighbours get a share, falling off as a bell curve of width
+    /// `sigma` (in keys)
+    Gaussian {
+        /// width of the curve, in keys
+        #[schemars(range(max = 12.0), extend("exclusiveMinimum" = 0.0))]
+        sigma: f32,
+    },
+    /// both neighbours get a share in proportion to how close they are,
+    /// shares always sum to 1
+    Triangular,
+    /// only the closest key gets a share, falling off sharply as the
+    /// frequency moves away from the key's pitch
+    Exponential {
+        /// how quickly the share falls off, bigger is sharper
+        #[schemars(extend("exclusiveMinimum" = 0.0))]
+        steepness: f32,
+    },
+}
+
+impl Default for Weighting {
+    fn default() -> Self {
+        Weighting::Exponential { steepness: 20.0 }
+    }
+}
+
+/// the lowest key (1-88) that's analysed, see `MIN_FREQUENCY`
 pub fn min_key() -> usize {
     frequency_to_key_number(MIN_FREQUENCY).round() as usize
 }
 
+/// how many keys are analysed, one bin for each
 pub fn num_keys() -> usize {
     NUM_KEYS - min_key()
 }
 
+/// the key (1-88) shown by the given bin
+pub fn bin_key_number(bin_index: BinIndex) -> KeyIndex {
+    bin_index + 1 + min_key()
+}
+
+/// Share the energy of `spectrum` out over the first `num_bins` keys
+/// analysed, with the config's `weighting`, then scale them if `scale` is
+/// on.
 pub fn bin_magnitudes(
     bins: &mut [f32],
-    spectrum: FrequencySpectrum,
+    spectrum: &FrequencySpectrum,
     num_bins: usize,
     display_config: &DisplayConfig,
 ) {
@@ -35,16 +91,28 @@ pub fn bin_magnitudes(
     let mut max = 0.0f32;
 
     for (freq, value) in spectrum.data().iter() {
-        let (key_number, decay) = frequency_to_nearest_key(freq.val());
-        let bin_index = (key_number - 1 - min_key) as BinIndex;
-        // if value.val() > (1.0 - display_config.sensitivity) {
-        if bin_index < num_bins {
-            bins[bin_index] += decay * value.val();
-            if bins[bin_index] > max {
-                max = bins[bin_index];
+        // a scaling that lets through negative or non-finite values would
+        // otherwise pull keys below zero or poison the whole strip
+        let value = value.val();
+        if !value.is_finite() || value <= 0.0 {
+            continue;
+        }
+        for (key_number, weight) in key_weights(freq.val(), display_config.weighting) {
+            if weight <= 0.0 {
+                continue;
+            }
+            // if value.val() > (1.0 - display_config.sensitivity) {
+            let bin_index: Option<BinIndex> = key_number.checked_sub(1 + min_key);
+            if let Some(bin_index) = bin_index
+                && bin_index < num_bins
+            {
+                bins[bin_index] += weight * value;
+                if bins[bin_index] > max {
+                    max = bins[bin_index];
+                }
             }
+            // }
         }
-        // }
     }
     if display_config.scale && max > 0.01 {
         for val in bins.iter_mut() {
@@ -53,6 +121,8 @@ pub fn bin_magnitudes(
     }
 }
 
+/// whether the key (1-88) is white or black, panicking for any other
+/// number
 pub fn key_colour(key_number: KeyIndex) -> KeyColour {
     if !(1..=88).contains(&key_number) {
         panic!("key number should be 1-88 inclusive");
@@ -72,39 +142,74 @@ fn get_note_index_in_octave(key_number: KeyIndex) -> usize {
     }
     (key_number - 4) % 12
 }
+// The keys below and above `frequency` with the share of the bin's energy
+// each should receive
+fn key_weights(frequency: f32, weighting: Weighting) -> [(KeyIndex, f32); 2] {
+    let key_position = frequency_to_key_number(frequency);
+    let lower = key_position.floor();
+    // distance from the lower key, 0..1
+    let offset = key_position - lower;
+    let lower = lower as KeyIndex;
+    let upper = lower + 1;
+
+    match weighting {
+        Weighting::Nearest => {
+            if offset < 0.5 {
+                [(lower, 1.0), (upper, 0.0)]
+            } else {
+                [(lower, 0.0), (upper, 1.0)]
+            }
+        }
+        Weighting::Gaussian { sigma } => [
+            (lower, normal_decay(offset, sigma)),
+            (upper, normal_decay(1.0 - offset, sigma)),
+        ],
+        Weighting::Triangular => [(lower, 1.0 - offset), (upper, offset)],
+        Weighting::Exponential { steepness } => {
+            let (key, weight) = frequency_to_nearest_key(frequency, steepness);
+            if key == lower {
+                [(lower, weight), (upper, 0.0)]
+            } else {
+                [(lower, 0.0), (upper, weight)]
+            }
+        }
+    }
+}
+
 // Function to get the nearest integer key number
-fn frequency_to_nearest_key(frequency: f32) -> (KeyIndex, f32) {
+fn frequency_to_nearest_key(frequency: f32, steepness: f32) -> (KeyIndex, f32) {
     let key_position = frequency_to_key_number(frequency);
     let key = key_position.round() as usize;
     let diff = key as f32 - key_position;
-    // let decay = normal_decay(diff, SIGMA);
-    let decay = exponential_decay(diff, 20.0);
+    let decay = exponential_decay(diff, steepness);
     (key, decay)
 }
 
-fn frequency_to_key_number(frequency: f32) -> f32 {
+/// the key (1-88) for `frequency` in Hz, fractional between keys
+pub fn frequency_to_key_number(frequency: f32) -> f32 {
     12.0 * (frequency / 440.0).log2() + 49.0
 }
 
-#[allow(dead_code)]
-fn key_number_to_frequency(key: usize) -> f32 {
+/// the frequency in Hz of the key (1-88), tuned to A440
+pub fn key_number_to_frequency(key: usize) -> f32 {
     (440.0 * 2.0_f64.powf((key as f64 - 49.0) / 12.0)) as f32
 }
 
+// decays are symmetric: a key is weighted the same whether the frequency is
+// sharp or flat of it
 fn exponential_decay(x: f32, steepness: f32) -> f32 {
-    let x = x.clamp(0.0, 1.0);
+    let x = x.abs().min(1.0);
     (-steepness * x).exp()
 }
 
-#[allow(dead_code)]
 fn normal_decay(x: f32, sigma: f32) -> f32 {
-    let x = x.clamp(0.0, 1.0);
+    let x = x.abs().min(1.0);
     let gaussian = (-0.5 * (x / sigma).powi(2)).exp();
     // Normalize so that f(0) = 1
     gaussian / ((-0.5 * (0.0 / sigma).powi(2)).exp())
 }
 
-#[allow(dead_code)]
+/// the key's pitch class, an index into `NOTE_NAMES` with A as 0
 pub fn key_number_to_index(key_number: KeyIndex) -> usize {
     let key_index = key_number - 1;
     let note_index = if key_index < 3 {
@@ -300,8 +405,43 @@ mod tests {
 
     #[test]
     fn test_frequency_to_nearest_key() {
-        assert_eq!(frequency_to_nearest_key(27.5), (1, 1.0));
-        assert_eq!(frequency_to_nearest_key(170.0), (33, 0.30295658));
+        assert_eq!(frequency_to_nearest_key(27.5, 20.0), (1, 1.0));
+        assert_eq!(frequency_to_nearest_key(170.0, 20.0), (33, 9.3969786e-5));
+        // flat and sharp of a key by the same amount weigh the same
+        let (_, flat) = frequency_to_nearest_key(key_number_to_frequency(40) * 0.99, 20.0);
+        let (_, sharp) = frequency_to_nearest_key(key_number_to_frequency(40) * 1.01, 20.0);
+        assert!((flat - sharp).abs() < 0.01);
+        assert!(flat < 1.0);
+    }
+
+    #[test]
+    fn test_key_weights() {
+        // a quarter-tone above A4
+        let freq = 440.0 * 2.0_f32.powf(0.5 / 12.0);
+        assert_eq!(
+            key_weights(440.0, Weighting::Nearest),
+            [(49, 1.0), (50, 0.0)]
+        );
+
+        let [(49, lower), (50, upper)] = key_weights(freq, Weighting::Triangular) else {
+            panic!("wrong keys");
+        };
+        assert!((lower - 0.5).abs() < 0.001);
+        assert!((upper - 0.5).abs() < 0.001);
+
+        let [(49, lower), (50, upper)] = key_weights(freq, Weighting::Gaussian { sigma: 0.5 })
+        else {
+            panic!("wrong keys");
+        };
+        assert!((lower - upper).abs() < 0.001);
+        assert!(lower > 0.0 && lower < 1.0);
+
+        let [(_, lower), (_, upper)] = key_weights(
+            key_number_to_frequency(49) * 1.01,
+            Weighting::Exponential { steepness: 20.0 },
+        );
+        assert!(lower > 0.0);
+        assert_eq!(upper, 0.0);
     }
 
     #[test]
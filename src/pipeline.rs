@@ -0,0 +1,324 @@
+use std::borrow::Cow;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use serde_json::{Value, json};
+
+use crate::analysis::{self, Input, SAMPLE_SIZE};
+use crate::audio::AudioInput;
+use crate::beat::{BeatEffect, BeatTracker};
+use crate::chord::ChordTracker;
+use crate::control::{self, Command, State};
+use crate::display::{Display, DisplayConfig, KeyColour, Mode};
+use crate::frames::{Recorder, Replay};
+use crate::lesson::Lesson;
+use crate::midi::Keyboard;
+use crate::midi_output::{NoteDetector, Output};
+use crate::musical_key::KeyEstimator;
+use crate::session::SessionLog;
+use crate::telemetry::Telemetry;
+use crate::{chroma, piano, report, tuner};
+
+// how fast out-of-scale notes flash in overlay mode
+const FLASH_INTERVAL: Duration = Duration::from_millis(125);
+
+/// Where the per-key levels come from.
+pub enum Source {
+    /// Live audio, analysed into levels every frame
+    Audio(AudioInput),
+    /// A MIDI instrument or file playing into a keyboard
+    Midi(Arc<Mutex<Keyboard>>),
+    /// Levels recorded earlier with a `Recorder`
+    Replay(Replay),
+}
+
+/// Everything between a `Source` and a `Display`: analysis, the trackers
+/// that follow chords, key and beat, the display modes and effects, and the
+/// optional outputs. Call `frame` in a loop.
+pub struct Pipeline {
+    source: Source,
+    bins: Vec<f32>,
+    peak_magnitudes: Vec<f32>,
+    // colours for single keys worked out by the analysis this frame, used
+    // in place of white/black
+    bin_colours: Vec<Option<KeyColour>>,
+    samples: Vec<f32>,
+    chord_tracker: ChordTracker,
+    key_estimator: KeyEstimator,
+    beat_tracker: BeatTracker,
+    note_detector: NoteDetector,
+    last_frame: Instant,
+    started: Instant,
+    state: State,
+    warned_no_lesson: bool,
+    /// where notes picked out of the levels are sent
+    pub midi_output: Option<Output>,
+    /// the lesson shown in lesson mode
+    pub lesson: Option<Lesson>,
+    /// keeps statistics on each session of playing
+    pub session_log: Option<SessionLog>,
+    /// saves the levels for replaying later
+    pub recorder: Option<Recorder>,
+    /// reports how the pipeline is keeping up
+    pub telemetry: Option<Telemetry>,
+}
+
+impl Pipeline {
+    /// A live pipeline reading from `source`, with none of the optional
+    /// outputs. Set the public fields to add them.
+    pub fn new(source: Source) -> Self {
+        let num_bins = piano::num_keys();
+        Pipeline {
+            source,
+            bins: vec![0.0; num_bins],
+            peak_magnitudes: vec![0.0; num_bins],
+            bin_colours: Vec::new(),
+            samples: vec![0.0; SAMPLE_SIZE],
+            chord_tracker: ChordTracker::new(),
+            key_estimator: KeyEstimator::new(),
+            beat_tracker: BeatTracker::new(),
+            note_detector: NoteDetector::new(),
+            last_frame: Instant::now(),
+            started: Instant::now(),
+            state: State::Live,
+            warned_no_lesson: false,
+            midi_output: None,
+            lesson: None,
+            session_log: None,
+            recorder: None,
+            telemetry: None,
+        }
+    }
+
+    /// The per-key levels from the last frame, before any display mode.
+    pub fn bins(&self) -> &[f32] {
+        &self.bins
+    }
+
+    /// Whether the pipeline is live, paused or showing something else.
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    /// Carry out a command from the control protocol and return the reply.
+    pub fn command(&mut self, command: &Command, config: &DisplayConfig) -> Result<Value, String> {
+        match *command {
+            Command::Pause => self.state = State::Paused,
+            Command::Resume => self.state = State::Live,
+            Command::Blackout => self.state = State::Blackout,
+            Command::TestPattern => {
+                self.state = State::TestPattern {
+                    since: Instant::now(),
+                }
+            }
+            Command::Identify { key, seconds } => {
+                if control::key_bin(key).is_none() {
+                    return Err(format!("key {} is not on the strip", key));
+                }
+                let seconds = Duration::try_from_secs_f32(seconds)
+                    .map_err(|_| format!("can't identify a key for {} seconds", seconds))?;
+                self.state = State::Identify {
+                    key,
+                    until: Instant::now() + seconds,
+                    previous: self.state.previous(),
+                };
+            }
+            Command::State => {
+                let mut reply = json!(self.state);
+                reply["config"] = json!(config);
+                return Ok(reply);
+            }
+            Command::Snapshot => {
+                return Ok(json!({
+                    "first_key": piano::bin_key_number(0),
+                    "levels": self.bins,
+                }));
+            }
+        }
+        Ok(json!(self.state))
+    }
+
+    /// Save anything the outputs are holding on to, before exiting.
+    pub fn finish(&mut self) {
+        if let Some(output) = &mut self.midi_output {
+            output.flush();
+        }
+    }
+
+    /// Draw the next frame on `display`: live analysis of the source, or
+    /// whatever a command asked for instead. A replayed capture can replace
+    /// `config`. Fails if the source has stopped working, e.g. the audio
+    /// input has gone away.
+    pub fn frame<D: Display>(
+        &mut self,
+        config: &mut DisplayConfig,
+        display: &mut D,
+    ) -> Result<(), String> {
+        let started = Instant::now();
+        self.draw(config, display)?;
+        if let Some(telemetry) = &mut self.telemetry {
+            telemetry.frame(started.elapsed());
+            if telemetry.due() {
+                let overruns = match &self.source {
+                    Source::Audio(input) => Some(input.overruns()),
+                    _ => None,
+                };
+                let notes = self.note_detector.held();
+                telemetry.status(config, overruns, notes, self.state).send();
+            }
+        }
+        Ok(())
+    }
+
+    fn draw<D: Display>(
+        &mut self,
+        config: &mut DisplayConfig,
+        display: &mut D,
+    ) -> Result<(), String> {
+        if let State::Identify {
+            until, previous, ..
+        } = self.state
+            && Instant::now() >= until
+        {
+            self.state = previous.into();
+        }
+        if self.state == State::Live {
+            return self.analyse(config, display);
+        }
+
+        // the source isn't read, so the trackers shouldn't see the gap
+        self.last_frame = Instant::now();
+        let mut shown = vec![0.0; self.bins.len()];
+        match self.state {
+            State::Live | State::Paused => return Ok(()),
+            State::Blackout => {}
+            State::TestPattern { since } => control::test_pattern(&mut shown, since.elapsed()),
+            State::Identify { key, until, .. } => {
+                if let Some(bin) = control::key_bin(key) {
+                    // counting down works just as well for flashing
+                    control::identify(
+                        &mut shown,
+                        bin,
+                        until.saturating_duration_since(Instant::now()),
+                    );
+                }
+            }
+        }
+        // no fading, so the pattern switches off cleanly
+        self.peak_magnitudes.fill(0.0);
+        display.visualize_bins(&shown, &[], &mut self.peak_magnitudes, config);
+        Ok(())
+    }
+
+    fn analyse<D: Display>(
+        &mut self,
+        config: &mut DisplayConfig,
+        display: &mut D,
+    ) -> Result<(), String> {
+        let elapsed = self.last_frame.elapsed();
+        self.last_frame = Instant::now();
+        let bins = &mut self.bins;
+        let mut input = Input::Replay;
+
+        let pitch = match &mut self.source {
+            Source::Audio(audio) => {
+                audio.read(&mut self.samples)?;
+                input = Input::Audio {
+                    rms: analysis::rms(&self.samples),
+                };
+                if let Some(telemetry) = &mut self.telemetry {
+                    telemetry.input(&self.samples);
+                }
+                analysis::analyse(&self.samples, audio.sample_rate(), bins, config)
+            }
+            Source::Replay(replay) => {
+                if let Some(recorded) = replay.advance(elapsed, bins) {
+                    *config = recorded;
+                }
+                None
+            }
+            Source::Midi(keyboard) => {
+                input = Input::Midi;
+                if let Ok(keyboard) = keyboard.lock() {
+                    keyboard.levels(bins);
+                }
+                None
+            }
+        };
+        if let Some(recorder) = &mut self.recorder {
+            recorder.record(bins, config);
+        }
+        let notes = self.note_detector.update(bins, input);
+        if let Some(output) = &mut self.midi_output {
+            output.send(&notes);
+        }
+        let chroma = chroma::chroma(bins);
+        let chord = self.chord_tracker.update(&chroma, input.quiet());
+        let musical_key = self.key_estimator.update(&chroma, elapsed, input.quiet());
+        if let Some(log) = &mut self.session_log {
+            log.update(bins, input, elapsed);
+        }
+        let beat = self.beat_tracker.update(bins, elapsed);
+
+        // the displays are drawn from a copy so the levels reported by
+        // `bins` stay as analysed
+        let mut shown = Cow::Borrowed(&self.bins[..]);
+        let mut mode_colours = None;
+        match config.mode {
+            Mode::Keys => {}
+            Mode::Chroma => chroma::spread(&chroma, shown.to_mut()),
+            Mode::Tuner => {
+                mode_colours = Some(tuner::show(pitch, shown.to_mut()));
+            }
+            Mode::Overlay => {
+                let flashes = self.started.elapsed().as_millis() / FLASH_INTERVAL.as_millis();
+                let flash = flashes.is_multiple_of(2);
+                mode_colours = Some(config.overlay.show(shown.to_mut(), flash));
+            }
+            Mode::Lesson => match &mut self.lesson {
+                Some(lesson) => mode_colours = Some(lesson.update(shown.to_mut(), input)),
+                None if !self.warned_no_lesson => {
+                    report::log("lesson mode needs a LESSON_FILE, showing the live levels");
+                    self.warned_no_lesson = true;
+                }
+                None => {}
+            },
+        }
+
+        let mut config = Cow::Borrowed(&*config);
+        if let Some(hue) = chord.and_then(|c| c.hue(config.chord_colour)) {
+            config = Cow::Owned(config.tinted(hue));
+        }
+        if let Some(beat) = beat {
+            match config.beat_effect {
+                BeatEffect::Off => {}
+                BeatEffect::Pulse { amount } => {
+                    let pulse = 1.0 + amount * (1.0 - beat.phase).powi(2);
+                    config.to_mut().brightness *= pulse;
+                }
+                BeatEffect::ColourStep { degrees } => {
+                    config = Cow::Owned(config.hue_shifted(beat.count as f32 * degrees));
+                }
+            }
+        }
+        self.bin_colours.clear();
+        if config.key_palette
+            && let Some(key) = musical_key
+        {
+            let out_of_key = &config.out_of_key;
+            self.bin_colours.extend((0..shown.len()).map(|i| {
+                (!key.contains_key_number(piano::bin_key_number(i))).then(|| out_of_key.clone())
+            }));
+        }
+        if let Some(colours) = mode_colours {
+            self.bin_colours = colours;
+        }
+        display.visualize_bins(
+            &shown,
+            &self.bin_colours,
+            &mut self.peak_magnitudes,
+            &config,
+        );
+        Ok(())
+    }
+}
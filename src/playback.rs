@@ -0,0 +1,279 @@
+use std::error::Error;
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use midly::{MetaMessage, MidiMessage, Smf, Timing, TrackEventKind};
+use schemars::JsonSchema;
+use serde::Deserialize;
+
+use crate::midi::Keyboard;
+
+// microseconds per beat until the file says otherwise, 120 BPM
+const DEFAULT_TEMPO: u32 = 500_000;
+const TICK: Duration = Duration::from_millis(2);
+
+/// a channel message from the file, timed in seconds from the start
+#[derive(Debug, Clone, PartialEq)]
+pub struct Event {
+    /// seconds from the start of the file
+    pub time: f32,
+    /// the raw MIDI message
+    pub message: [u8; 3],
+}
+
+/// playback commands accepted on stdin, e.g. `{"playback":{"action":"seek","seconds":30}}`
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, JsonSchema)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum Control {
+    /// carry on playing, from the start if the file has finished
+    Play,
+    /// stop where it is
+    Pause,
+    /// jump to a point in the file
+    Seek {
+        /// from the start of the file
+        #[schemars(range(min = 0.0))]
+        seconds: f32,
+    },
+    /// play faster or slower
+    Speed {
+        /// 1 for the file's own tempo, 2 for twice as fast
+        #[schemars(range(max = 10.0), extend("exclusiveMinimum" = 0.0))]
+        factor: f32,
+    },
+}
+
+/// a playback command as it arrives on stdin
+#[derive(Deserialize, Debug, JsonSchema)]
+pub struct Message {
+    /// what to do
+    pub playback: Control,
+}
+
+/// Read the note and pedal messages of a MIDI file, in the order they're
+/// played.
+pub fn load(path: &str) -> Result<Vec<Event>, Box<dyn Error>> {
+    let bytes = std::fs::read(path)?;
+    let smf = Smf::parse(&bytes)?;
+    Ok(events(&smf))
+}
+
+// Flatten all the tracks into one list of note and pedal messages, with
+// ticks converted to seconds through the tempo map.
+fn events(smf: &Smf) -> Vec<Event> {
+    let mut timeline = Vec::new();
+    for track in &smf.tracks {
+        let mut tick: u64 = 0;
+        for event in track {
+            tick += event.delta.as_int() as u64;
+            timeline.push((tick, event.kind));
+        }
+    }
+    // stable, so events on the same tick keep their track order
+    timeline.sort_by_key(|(tick, _)| *tick);
+
+    let mut tempo = DEFAULT_TEMPO;
+    let mut last_tick = 0;
+    let mut time = 0.0f64;
+    let mut events = Vec::new();
+    for (tick, kind) in timeline {
+        let ticks = (tick - last_tick) as f64;
+        time += match smf.header.timing {
+            Timing::Metrical(ppq) => ticks * tempo as f64 / 1_000_000.0 / ppq.as_int() as f64,
+            Timing::Timecode(fps, subframes) => ticks / (fps.as_f32() as f64 * subframes as f64),
+        };
+        last_tick = tick;
+
+        let message = match kind {
+            TrackEventKind::Meta(MetaMessage::Tempo(t)) => {
+                tempo = t.as_int();
+                None
+            }
+            TrackEventKind::Midi { channel, message } => {
+                let channel = channel.as_int();
+                match message {
+                    MidiMessage::NoteOn { key, vel } => {
+                        Some([0x90 | channel, key.as_int(), vel.as_int()])
+                    }
+                    MidiMessage::NoteOff { key, vel } => {
+                        Some([0x80 | channel, key.as_int(), vel.as_int()])
+                    }
+                    MidiMessage::Controller { controller, value } => {
+                        Some([0xB0 | channel, controller.as_int(), value.as_int()])
+                    }
+                    _ => None,
+                }
+            }
+            _ => None,
+        };
+        if let Some(message) = message {
+            events.push(Event {
+                time: time as f32,
+                message,
+            });
+        }
+    }
+    events
+}
+
+/// Plays the events into a `Keyboard` as if they came from an instrument.
+pub struct Player {
+    events: Vec<Event>,
+    keyboard: Arc<Mutex<Keyboard>>,
+    // seconds into the piece
+    position: f32,
+    // index of the next event to play
+    next: usize,
+    playing: bool,
+    speed: f32,
+}
+
+impl Player {
+    /// A player that starts at the beginning of `events`, playing them into
+    /// `keyboard`.
+    pub fn new(events: Vec<Event>, keyboard: Arc<Mutex<Keyboard>>) -> Self {
+        Player {
+            events,
+            keyboard,
+            position: 0.0,
+            next: 0,
+            playing: true,
+            speed: 1.0,
+        }
+    }
+
+    /// Carry out a playback command.
+    pub fn control(&mut self, control: Control) {
+        match control {
+            Control::Play => {
+                if self.next >= self.events.len() {
+                    self.seek(0.0);
+                }
+                self.playing = true;
+            }
+            Control::Pause => self.playing = false,
+            Control::Seek { seconds } => self.seek(seconds.max(0.0)),
+            Control::Speed { factor } => self.speed = factor.max(0.0),
+        }
+    }
+
+    /// Play the events due in the next `elapsed`, at the current speed.
+    pub fn advance(&mut self, elapsed: Duration) {
+        if !self.playing {
+            return;
+        }
+        self.position += elapsed.as_secs_f32() * self.speed;
+        self.play_until(self.position);
+        if self.next >= self.events.len() {
+            self.playing = false;
+        }
+    }
+
+    // Rebuild the keyboard state at `seconds` by replaying everything
+    // before it, so notes and pedal held across the seek point stay down.
+    fn seek(&mut self, seconds: f32) {
+        if let Ok(mut keyboard) = self.keyboard.lock() {
+            keyboard.reset();
+        }
+        self.next = 0;
+        self.position = seconds;
+        self.play_until(seconds);
+    }
+
+    fn play_until(&mut self, seconds: f32) {
+        let Ok(mut keyboard) = self.keyboard.lock() else {
+            return;
+        };
+        while let Some(event) = self.events.get(self.next)
+            && event.time <= seconds
+        {
+            keyboard.handle(&event.message);
+            self.next += 1;
+        }
+    }
+}
+
+/// Play the events on a thread of their own, returning the channel that
+/// controls it.
+pub fn start(events: Vec<Event>, keyboard: Arc<Mutex<Keyboard>>) -> Sender<Control> {
+    let (tx, rx): (Sender<Control>, Receiver<Control>) = mpsc::channel();
+    let mut player = Player::new(events, keyboard);
+    thread::spawn(move || {
+        let mut last = Instant::now();
+        loop {
+            thread::sleep(TICK);
+            loop {
+                match rx.try_recv() {
+                    Ok(control) => player.control(control),
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => return,
+                }
+            }
+            player.advance(last.elapsed());
+            last = Instant::now();
+        }
+    });
+    tx
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use midly::num::{u4, u7, u15, u24, u28};
+    use midly::{Format, Header, TrackEvent};
+
+    fn note_on(delta: u32, key: u8) -> TrackEvent<'static> {
+        TrackEvent {
+            delta: u28::new(delta),
+            kind: TrackEventKind::Midi {
+                channel: u4::new(0),
+                message: MidiMessage::NoteOn {
+                    key: u7::new(key),
+                    vel: u7::new(100),
+                },
+            },
+        }
+    }
+
+    fn smf() -> Smf<'static> {
+        let tempo = |delta, t| TrackEvent {
+            delta: u28::new(delta),
+            kind: TrackEventKind::Meta(MetaMessage::Tempo(u24::new(t))),
+        };
+        Smf {
+            header: Header::new(Format::Parallel, Timing::Metrical(u15::new(480))),
+            tracks: vec![
+                // halve the tempo after the first beat
+                vec![tempo(0, 500_000), tempo(480, 1_000_000)],
+                vec![note_on(0, 60), note_on(480, 62), note_on(480, 64)],
+            ],
+        }
+    }
+
+    #[test]
+    fn test_events_follow_tempo_map() {
+        let times: Vec<f32> = events(&smf()).iter().map(|e| e.time).collect();
+        assert_eq!(times, vec![0.0, 0.5, 1.5]);
+    }
+
+    #[test]
+    fn test_seek_restores_held_notes() {
+        let keyboard = Arc::new(Mutex::new(Keyboard::new()));
+        let mut player = Player::new(events(&smf()), Arc::clone(&keyboard));
+        player.control(Control::Seek { seconds: 1.0 });
+        let mut bins = vec![0.0; crate::piano::num_keys()];
+        keyboard.lock().unwrap().levels(&mut bins);
+        let first = crate::piano::bin_key_number(0);
+        // middle C and D are down, E hasn't been played yet
+        assert!(bins[40 - first] > 0.0);
+        assert!(bins[42 - first] > 0.0);
+        assert_eq!(bins[44 - first], 0.0);
+
+        player.advance(Duration::from_secs(1));
+        keyboard.lock().unwrap().levels(&mut bins);
+        assert!(bins[44 - first] > 0.0);
+        assert!(!player.playing);
+    }
+}
@@ -0,0 +1,254 @@
+use std::fmt::Write as _;
+use std::io;
+use std::net::UdpSocket;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+use smart_leds::RGB8;
+
+use crate::display::{Display, DisplayConfig, KeyColour};
+use crate::strip;
+use crate::{piano, report};
+
+/// Where preview frames go: stdout alongside the other messages, or JSON
+/// datagrams to a local UDP port so a busy preview can't hold up stdout.
+pub enum Sink {
+    /// sent as messages with everything else, see `report::send`
+    Stdout,
+    /// sent to the socket's connected address
+    Udp(UdpSocket),
+}
+
+impl Sink {
+    /// `target` is "-" for stdout or an address like "127.0.0.1:9000"
+    pub fn open(target: &str) -> io::Result<Self> {
+        if target == "-" {
+            return Ok(Sink::Stdout);
+        }
+        let socket = UdpSocket::bind("127.0.0.1:0")?;
+        socket.connect(target)?;
+        Ok(Sink::Udp(socket))
+    }
+
+    fn send(&self, frame: &Frame) {
+        let message = Message { frame };
+        match self {
+            Sink::Stdout => report::send(&message),
+            Sink::Udp(socket) => {
+                // dropped frames don't matter, the next one replaces them
+                if let Ok(json) = serde_json::to_vec(&message) {
+                    let _ = socket.send(&json);
+                }
+            }
+        }
+    }
+}
+
+/// one frame of the preview, sent as `{"frame":{...}}`
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Frame {
+    /// every LED on the strip as "rrggbb" hex, what the strip is showing
+    pub leds: String,
+    /// the key shown by the first level
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub first_key: Option<usize>,
+    /// the per-key levels the frame was drawn from
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub levels: Option<Vec<f32>>,
+}
+
+#[derive(Serialize)]
+struct Message<'a> {
+    frame: &'a Frame,
+}
+
+/// A display that passes everything on to `inner` and publishes a copy of
+/// the LED colours at most `fps` times a second, for a live preview in the
+/// web UI. `num_leds` should match the strip's.
+pub struct Preview<D> {
+    inner: D,
+    sink: Sink,
+    interval: Duration,
+    levels: bool,
+    last_sent: Option<Instant>,
+    data: Vec<RGB8>,
+}
+
+impl<D: Display> Preview<D> {
+    /// Wrap `inner`, sending previews to `sink`, with the levels too if
+    /// `levels` is set.
+    pub fn new(inner: D, sink: Sink, fps: f32, levels: bool, num_leds: usize) -> Self {
+        Preview {
+            inner,
+            sink,
+            interval: Duration::from_secs_f32(1.0 / fps.max(0.1)),
+            levels,
+            last_sent: None,
+            data: vec![RGB8::default(); num_leds],
+        }
+    }
+
+    fn due(&self) -> bool {
+        self.last_sent
+            .is_none_or(|sent| sent.elapsed() >= self.interval)
+    }
+}
+
+/// what would be sent for the given levels and peaks, the peaks are faded
+/// just as the strip fades them
+pub fn frame(
+    bins: &[f32],
+    colours: &[Option<KeyColour>],
+    peak_magnitudes: &mut [f32],
+    config: &DisplayConfig,
+    data: &mut [RGB8],
+    levels: bool,
+) -> Frame {
+    strip::render(bins, colours, peak_magnitudes, config, data);
+    let mut hex = String::with_capacity(data.len() * 6);
+    for led in data.iter() {
+        let _ = write!(hex, "{:02x}{:02x}{:02x}", led.r, led.g, led.b);
+    }
+    Frame {
+        leds: hex,
+        first_key: levels.then(|| piano::bin_key_number(0)),
+        levels: levels.then(|| bins.to_vec()),
+    }
+}
+
+impl<D: Display> Display for Preview<D> {
+    fn visualize_bins(
+        &mut self,
+        bins: &[f32],
+        colours: &[Option<KeyColour>],
+        peak_magnitudes: &mut Vec<f32>,
+        config: &DisplayConfig,
+    ) {
+        // render from the peaks as they were before this frame, so the
+        // preview fades exactly like the strip
+        let before = self.due().then(|| peak_magnitudes.clone());
+        self.inner
+            .visualize_bins(bins, colours, peak_magnitudes, config);
+        if let Some(mut peaks) = before {
+            let frame = frame(
+                bins,
+                colours,
+                &mut peaks,
+                config,
+                &mut self.data,
+                self.levels,
+            );
+            self.sink.send(&frame);
+            self.last_sent = Some(Instant::now());
+        }
+    }
+
+    fn reset(&mut self) {
+        self.inner.reset();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_frame() {
+        let bins = vec![0.0; piano::num_keys()];
+        let mut peaks = bins.clone();
+        let config = DisplayConfig::default();
+        let mut data = vec![RGB8::default(); config.layout.num_leds];
+        let frame = frame(&bins, &[], &mut peaks, &config, &mut data, false);
+        assert_eq!(frame.leds.len(), config.layout.num_leds * 6);
+        assert!(frame.levels.is_none());
+
+        let frame = super::frame(&bins, &[], &mut peaks, &config, &mut data, true);
+        assert_eq!(frame.levels.unwrap().len(), bins.len());
+        assert_eq!(frame.first_key, Some(piano::bin_key_number(0)));
+    }
+
+    #[test]
+    fn test_frame_colours() {
+        let bins = vec![1.0; piano::num_keys()];
+        let mut peaks = vec![0.0; bins.len()];
+        let config = DisplayConfig::default();
+        let mut data = vec![RGB8::default(); config.layout.num_leds];
+        let colours = [Some(KeyColour::new(120.0, 1.0))];
+        frame(&bins, &colours, &mut peaks, &config, &mut data, false);
+        // the first key is coloured green, the next keeps the default red
+        assert_eq!((data[5].r, data[5].b), (0, 0));
+        assert!(data[5].g > 0);
+        assert!(data[8].r > data[8].g);
+    }
+
+    #[test]
+    fn test_frame_key_widths() {
+        let bins = vec![1.0; piano::num_keys()];
+        let config = DisplayConfig::default();
+        let mut data = vec![RGB8::default(); config.layout.num_leds];
+        let is_black = |i| piano::key_colour(piano::bin_key_number(i)) == piano::KeyColour::Black;
+        let green = KeyColour::new(120.0, 1.0);
+        let colours: Vec<_> = (0..bins.len())
+            .map(|i| is_black(i).then(|| green.clone()))
+            .collect();
+        frame(
+            &bins,
+            &colours,
+            &mut bins.clone(),
+            &config,
+            &mut data,
+            false,
+        );
+        // black keys are one LED wide and white keys three, whichever key
+        // the strip starts at
+        let mut l = config.layout.offset;
+        for i in 0..bins.len() {
+            let width = if is_black(i) { 1 } else { 3 };
+            for led in &data[l..l + width] {
+                assert_eq!(
+                    led.g > led.r,
+                    is_black(i),
+                    "key {}",
+                    piano::bin_key_number(i)
+                );
+            }
+            l += width;
+        }
+    }
+
+    #[test]
+    fn test_frame_layout() {
+        let bins = vec![1.0; piano::num_keys()];
+        let mut config = DisplayConfig::default();
+        config.layout.offset = 0;
+        let mut data = vec![RGB8::default(); config.layout.num_leds];
+        frame(&bins, &[], &mut bins.clone(), &config, &mut data, false);
+        assert!(data[0].r > 0);
+
+        // keys past the end of the strip are left off
+        config.layout.offset = config.layout.num_leds - 1;
+        data.fill(RGB8::default());
+        frame(&bins, &[], &mut bins.clone(), &config, &mut data, false);
+        assert!(data[..config.layout.offset].iter().all(|led| led.r == 0));
+        assert!(data[config.layout.offset].r > 0);
+    }
+
+    #[test]
+    fn test_udp_sink() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        receiver
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let sink = Sink::open(&receiver.local_addr().unwrap().to_string()).unwrap();
+        let frame = Frame {
+            leds: "ff0000".to_string(),
+            first_key: None,
+            levels: None,
+        };
+        sink.send(&frame);
+        let mut buf = [0; 256];
+        let len = receiver.recv(&mut buf).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&buf[..len]).unwrap();
+        assert_eq!(json["frame"]["leds"], "ff0000");
+    }
+}
@@ -0,0 +1,390 @@
+use std::io::{self, BufRead};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::control::Command;
+use crate::display::{self, DisplayConfig};
+use crate::report::{self, Format};
+use crate::validation::{self, Violation};
+use crate::{etf, playback};
+
+/// longest message accepted on stdin, a full config is well under 2k
+pub const MAX_LINE: usize = 64 * 1024;
+
+// the key that picks the variant of the config's tagged enums
+const TAG: &str = "type";
+
+/// a message from the Elixir side, one line of JSON
+#[derive(Debug)]
+pub enum Request {
+    /// a JSON merge patch (RFC 7396) for the current config, see `apply`
+    Config(Value),
+    /// a command for the MIDI file being played
+    Playback(playback::Control),
+    /// a command, with the id to send back with its reply
+    Command {
+        /// sent back with the reply, if there was one
+        id: Option<Value>,
+        /// what to do
+        command: Command,
+    },
+}
+
+#[derive(Deserialize)]
+struct Tagged {
+    #[serde(default)]
+    id: Option<Value>,
+    #[serde(flatten)]
+    command: Command,
+}
+
+/// what kind of problem a line had
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorKind {
+    /// not JSON at all, or cut off part way through
+    Syntax,
+    /// JSON, but not a message we understand
+    Invalid,
+    /// longer than MAX_LINE
+    TooLong,
+    /// not UTF-8
+    Encoding,
+    /// a command that was understood but couldn't be carried out
+    Failed,
+}
+
+/// why a line was rejected, sent back as `{"error":{...}}`
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Error {
+    /// what kind of problem it was
+    pub kind: ErrorKind,
+    /// what was wrong, for people to read
+    pub message: String,
+    /// path to the offending value, e.g. `white.hue`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub field: Option<String>,
+    /// where a syntax error was found on the line
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub column: Option<usize>,
+    /// the id of the command that failed, sent alongside the error
+    #[serde(skip)]
+    pub id: Option<Value>,
+}
+
+#[derive(Serialize)]
+struct Reply<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<&'a Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reply: Option<&'a Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<&'a Error>,
+}
+
+impl Error {
+    fn new(kind: ErrorKind, message: impl Into<String>) -> Self {
+        Error {
+            kind,
+            message: message.into(),
+            field: None,
+            column: None,
+            id: None,
+        }
+    }
+
+    /// a command that couldn't be carried out
+    pub fn failed(id: Option<Value>, message: impl Into<String>) -> Self {
+        Error {
+            id,
+            ..Error::new(ErrorKind::Failed, message)
+        }
+    }
+
+    fn syntax(err: serde_json::Error) -> Self {
+        Error {
+            column: Some(err.column()),
+            ..Error::new(ErrorKind::Syntax, err.to_string())
+        }
+    }
+
+    fn invalid(err: serde_path_to_error::Error<serde_json::Error>) -> Self {
+        let path = err.path().to_string();
+        Error {
+            // "." is the path of the message as a whole
+            field: (path != ".").then_some(path),
+            ..Error::new(ErrorKind::Invalid, err.into_inner().to_string())
+        }
+    }
+
+    fn violation(violation: Violation) -> Self {
+        Error {
+            field: Some(violation.field),
+            ..Error::new(ErrorKind::Invalid, violation.message)
+        }
+    }
+
+    /// report the error to the Elixir side
+    pub fn send(&self) {
+        crate::report::send(&Reply {
+            id: self.id.as_ref(),
+            reply: None,
+            error: Some(self),
+        });
+    }
+}
+
+/// Answer a command, e.g. `{"id":7,"reply":{"state":"paused"}}`.
+pub fn reply(id: Option<&Value>, reply: &Value) {
+    crate::report::send(&Reply {
+        id,
+        reply: Some(reply),
+        error: None,
+    });
+}
+
+/// Decode one line of JSON, see `decode`.
+pub fn parse(line: &str) -> Result<Request, Error> {
+    let value: Value = serde_json::from_str(line).map_err(Error::syntax)?;
+    decode(value)
+}
+
+/// Work out what a message is asking for. Anything with a `command` key is a
+/// command, anything with a `playback` key is a playback command and
+/// everything else is a change to the config.
+pub fn decode(mut value: Value) -> Result<Request, Error> {
+    if !value.is_object() {
+        return Err(Error::new(ErrorKind::Invalid, "expected a JSON object"));
+    }
+    if value.get("command").is_some() {
+        let id = value.get("id").cloned();
+        check(validation::command_schema(), &mut value).map_err(|err| Error {
+            id: id.clone(),
+            ..err
+        })?;
+        let tagged: Tagged = serde_path_to_error::deserialize(value).map_err(|err| Error {
+            id,
+            ..Error::invalid(err)
+        })?;
+        Ok(Request::Command {
+            id: tagged.id,
+            command: tagged.command,
+        })
+    } else if value.get("playback").is_some() {
+        check(validation::playback_schema(), &mut value)?;
+        let message: playback::Message =
+            serde_path_to_error::deserialize(value).map_err(Error::invalid)?;
+        Ok(Request::Playback(message.playback))
+    } else {
+        Ok(Request::Config(value))
+    }
+}
+
+/// Load a config stored by this or an earlier build, such as DISPLAY_CONFIG
+/// or a config file, over the defaults. One without a version is from before
+/// the config was versioned and is upgraded, see `display::migrate`.
+pub fn load(stored: &Value) -> Result<DisplayConfig, Error> {
+    let mut stored = stored.clone();
+    upgrade(&mut stored)?;
+    apply(&DisplayConfig::default(), &stored)
+}
+
+/// Merge `patch` into `config`. Keys in the patch replace the current values,
+/// objects are merged key by key and `null` puts a field, nested or not, back
+/// to its default. A patch naming another variant of an option such as
+/// `weighting` replaces it outright. A complete config replaces everything.
+///
+/// A patch is taken to be for the current version unless it names an older
+/// one, or is a whole config from before versioning, and is then upgraded
+/// just as `load` would. The config is left alone if the result isn't valid.
+pub fn apply(config: &DisplayConfig, patch: &Value) -> Result<DisplayConfig, Error> {
+    let mut patch = patch.clone();
+    let versioned = patch
+        .get("version")
+        .is_some_and(|version| !version.is_null());
+    if versioned || display::is_unversioned(&patch) {
+        upgrade(&mut patch)?;
+    }
+    let to_value = |config: &DisplayConfig| {
+        serde_json::to_value(config).map_err(|err| Error::new(ErrorKind::Invalid, err.to_string()))
+    };
+    let mut merged = to_value(config)?;
+    let defaults = to_value(&DisplayConfig::default())?;
+    merge(&mut merged, &patch, Some(&defaults));
+    validation::check_fields(validation::config_schema(), &merged).map_err(Error::violation)?;
+    check(validation::config_schema(), &mut merged)?;
+    serde_path_to_error::deserialize(merged).map_err(Error::invalid)
+}
+
+// Hold `value` to the ranges in `schema`. Values that were clamped or
+// wrapped are only logged, the message still goes through.
+fn check(schema: &Value, value: &mut Value) -> Result<(), Error> {
+    let adjustments = validation::validate(schema, value).map_err(Error::violation)?;
+    for adjustment in adjustments {
+        report::log(&format!(
+            "{} is out of range, using {} instead of {}",
+            adjustment.field, adjustment.to, adjustment.from
+        ));
+    }
+    Ok(())
+}
+
+fn upgrade(config: &mut Value) -> Result<(), Error> {
+    display::migrate(config).map_err(|message| Error {
+        field: Some("version".to_string()),
+        ..Error::new(ErrorKind::Invalid, message)
+    })
+}
+
+// `defaults` is the same place in the default config, which a `null` in the
+// patch takes its value from
+fn merge(target: &mut Value, patch: &Value, defaults: Option<&Value>) {
+    let Value::Object(patch) = patch else {
+        *target = patch.clone();
+        return;
+    };
+    // the fields of one variant mean nothing to another
+    let retagged = patch
+        .get(TAG)
+        .is_some_and(|tag| target.get(TAG).is_some_and(|current| current != tag));
+    if retagged || !target.is_object() {
+        *target = Value::Object(Default::default());
+    }
+    if let Value::Object(target) = target {
+        for (key, value) in patch {
+            let default = defaults.and_then(|defaults| defaults.get(key));
+            match (value, default) {
+                (Value::Null, Some(default)) => {
+                    target.insert(key.clone(), default.clone());
+                }
+                (Value::Null, None) => {
+                    target.remove(key);
+                }
+                _ => merge(
+                    target.entry(key.as_str()).or_insert(Value::Null),
+                    value,
+                    default,
+                ),
+            }
+        }
+    }
+}
+
+/// Read and decode the next message, in whichever format the Elixir side is
+/// using. Blank lines are skipped. Returns `None` at the end of the input.
+pub fn read_request<R: BufRead>(
+    reader: &mut R,
+    format: Format,
+) -> io::Result<Option<Result<Request, Error>>> {
+    loop {
+        let message = match format {
+            Format::Json => match read_line(reader, MAX_LINE)? {
+                Some(Ok(line)) if line.trim().is_empty() => continue,
+                Some(line) => line.and_then(|line| parse(&line)),
+                None => return Ok(None),
+            },
+            Format::Etf => match etf::read_packet(reader, MAX_LINE)? {
+                Some(Ok(packet)) => etf::decode(&packet)
+                    .map_err(|message| Error::new(ErrorKind::Syntax, message))
+                    .and_then(decode),
+                Some(Err(_)) => Err(too_long_error(MAX_LINE)),
+                None => return Ok(None),
+            },
+        };
+        return Ok(Some(message));
+    }
+}
+
+fn too_long_error(limit: usize) -> Error {
+    Error::new(
+        ErrorKind::TooLong,
+        format!("message is longer than {} bytes", limit),
+    )
+}
+
+/// Read the next line from `reader`, at most `limit` bytes of it. A longer
+/// line is skipped up to its newline and reported as too long, so one bad
+/// write can't use up all the memory or knock the reader out of step.
+/// Returns `None` at the end of the input.
+pub fn read_line<R: BufRead>(
+    reader: &mut R,
+    limit: usize,
+) -> io::Result<Option<Result<String, Error>>> {
+    let mut line = Vec::new();
+    let mut too_long = false;
+    loop {
+        let available = match reader.fill_buf() {
+            Ok(available) => available,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        };
+        if available.is_empty() {
+            if line.is_empty() && !too_long {
+                return Ok(None);
+            }
+            break;
+        }
+        let (chunk, found) = match available.iter().position(|&b| b == b'\n') {
+            Some(end) => (&available[..end], true),
+            None => (available, false),
+        };
+        if !too_long {
+            if line.len() + chunk.len() > limit {
+                too_long = true;
+                line.clear();
+            } else {
+                line.extend_from_slice(chunk);
+            }
+        }
+        let used = chunk.len() + usize::from(found);
+        reader.consume(used);
+        if found {
+            break;
+        }
+    }
+
+    if too_long {
+        return Ok(Some(Err(too_long_error(limit))));
+    }
+    Ok(Some(String::from_utf8(line).map_err(|err| {
+        Error::new(ErrorKind::Encoding, err.to_string())
+    })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_merge() {
+        // examples from RFC 7396
+        let mut target = json!({"a": "b", "c": {"d": "e", "f": "g"}});
+        merge(&mut target, &json!({"a": "z", "c": {"f": null}}), None);
+        assert_eq!(target, json!({"a": "z", "c": {"d": "e"}}));
+
+        let mut target = json!({"a": [{"b": "c"}]});
+        merge(&mut target, &json!({"a": [1]}), None);
+        assert_eq!(target, json!({"a": [1]}));
+
+        let mut target = json!(["a", "b"]);
+        merge(&mut target, &json!({"a": {"b": "c"}}), None);
+        assert_eq!(target, json!({"a": {"b": "c"}}));
+
+        // nulls take the default where there is one
+        let mut target = json!({"c": {"d": "e", "f": "g"}});
+        let defaults = json!({"c": {"d": "x"}});
+        merge(
+            &mut target,
+            &json!({"c": {"d": null, "f": null}}),
+            Some(&defaults),
+        );
+        assert_eq!(target, json!({"c": {"d": "x"}}));
+
+        // another variant replaces the old one's fields
+        let mut target = json!({"type": "exponential", "steepness": 20});
+        merge(&mut target, &json!({"type": "gaussian", "sigma": 1}), None);
+        assert_eq!(target, json!({"type": "gaussian", "sigma": 1}));
+    }
+}
@@ -0,0 +1,96 @@
+use std::io::Write;
+use std::sync::{Mutex, OnceLock};
+
+use serde::Serialize;
+
+use crate::etf;
+
+/// how messages are exchanged with the Elixir side
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Format {
+    /// a line of JSON per message
+    #[default]
+    Json,
+    /// external term format in 4 byte length-prefixed packets, for a port
+    /// opened with `{:packet, 4}`
+    Etf,
+}
+
+impl Format {
+    /// from the CONTROL_FORMAT env var, "json" or "etf"
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "json" => Some(Format::Json),
+            "etf" => Some(Format::Etf),
+            _ => None,
+        }
+    }
+}
+
+static FORMAT: OnceLock<Format> = OnceLock::new();
+
+/// Pick the format for everything sent on stdout. Only the first call counts,
+/// it has to be made before anything is sent.
+pub fn set_format(format: Format) {
+    let _ = FORMAT.set(format);
+}
+
+/// the format set with `set_format`, JSON if it hasn't been
+pub fn format() -> Format {
+    FORMAT.get().copied().unwrap_or_default()
+}
+
+type Sink = Box<dyn Write + Send>;
+type Logger = Box<dyn Fn(&str) + Send + Sync>;
+
+static SINK: OnceLock<Mutex<Sink>> = OnceLock::new();
+static LOGGER: OnceLock<Logger> = OnceLock::new();
+
+/// Send every message to `sink`, stdout for the `leds` binary. Messages are
+/// dropped until a sink is set, so the library stays quiet when embedded.
+/// Only the first call counts.
+pub fn set_sink(sink: impl Write + Send + 'static) {
+    let _ = SINK.set(Mutex::new(Box::new(sink)));
+}
+
+/// Pass the library's log messages, such as a config value that had to be
+/// clamped, to `logger`; the `leds` binary writes them to stderr. Like
+/// messages, they are dropped until a logger is set. Only the first call
+/// counts.
+pub fn set_logger(logger: impl Fn(&str) + Send + Sync + 'static) {
+    let _ = LOGGER.set(Box::new(logger));
+}
+
+/// Log a message for whoever is running the lights, see `set_logger`.
+pub fn log(message: &str) {
+    if let Some(logger) = LOGGER.get() {
+        logger(message);
+    }
+}
+
+/// Write a message for the Elixir side to the sink.
+pub fn send<T: Serialize>(message: &T) {
+    let Some(sink) = SINK.get() else {
+        return;
+    };
+    // a panic while writing leaves nothing half done worth worrying about
+    let mut sink = sink.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    let result = match format() {
+        Format::Json => serde_json::to_string(message)
+            .map_err(|err| err.to_string())
+            .and_then(|json| {
+                writeln!(sink, "{}", json)
+                    .and_then(|_| sink.flush())
+                    .map_err(|err| err.to_string())
+            }),
+        Format::Etf => serde_json::to_value(message)
+            .map_err(|err| err.to_string())
+            .and_then(|value| etf::encode(&value))
+            .and_then(|packet| {
+                etf::write_packet(&mut *sink, &packet).map_err(|err| err.to_string())
+            }),
+    };
+    if let Err(err) = result {
+        log(&format!("failed to send report: {}", err));
+    }
+}
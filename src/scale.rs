@@ -0,0 +1,199 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::chord::Quality;
+use crate::display::KeyColour;
+use crate::piano;
+
+// level the keys of the scale are lit at when nobody's playing them
+const DIM_LEVEL: f32 = 0.1;
+// a key outside the scale counts as played above this level
+const PLAYED_LEVEL: f32 = 0.3;
+const WARNING_HUE: f32 = 0.0;
+
+/// a scale to practise, from whichever root
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Scale {
+    /// the white keys from C
+    Major,
+    /// the white keys from A
+    NaturalMinor,
+    /// natural minor with a raised seventh
+    HarmonicMinor,
+    /// natural minor with a raised sixth and seventh
+    MelodicMinor,
+    /// the white keys from D
+    Dorian,
+    /// the white keys from E
+    Phrygian,
+    /// the white keys from F
+    Lydian,
+    /// the white keys from G
+    Mixolydian,
+    /// the white keys from B
+    Locrian,
+    /// the black keys from F#
+    MajorPentatonic,
+    /// the black keys from D#
+    MinorPentatonic,
+    /// minor pentatonic with a flattened fifth
+    Blues,
+}
+
+impl Scale {
+    /// semitones above the root
+    pub fn intervals(&self) -> &'static [usize] {
+        match self {
+            Scale::Major => &[0, 2, 4, 5, 7, 9, 11],
+            Scale::NaturalMinor => &[0, 2, 3, 5, 7, 8, 10],
+            Scale::HarmonicMinor => &[0, 2, 3, 5, 7, 8, 11],
+            Scale::MelodicMinor => &[0, 2, 3, 5, 7, 9, 11],
+            Scale::Dorian => &[0, 2, 3, 5, 7, 9, 10],
+            Scale::Phrygian => &[0, 1, 3, 5, 7, 8, 10],
+            Scale::Lydian => &[0, 2, 4, 6, 7, 9, 11],
+            Scale::Mixolydian => &[0, 2, 4, 5, 7, 9, 10],
+            Scale::Locrian => &[0, 1, 3, 5, 6, 8, 10],
+            Scale::MajorPentatonic => &[0, 2, 4, 7, 9],
+            Scale::MinorPentatonic => &[0, 3, 5, 7, 10],
+            Scale::Blues => &[0, 3, 5, 6, 7, 10],
+        }
+    }
+}
+
+/// the notes to practise, e.g. `{"scale":"dorian"}` or `{"chord":"minor7"}`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Notes {
+    /// the notes of a scale
+    Scale(Scale),
+    /// the notes of a chord
+    Chord(Quality),
+}
+
+impl Notes {
+    fn intervals(&self) -> &'static [usize] {
+        match self {
+            Notes::Scale(scale) => scale.intervals(),
+            Notes::Chord(quality) => quality.intervals(),
+        }
+    }
+}
+
+/// A note name, sharp or flat. The variants are in the order of
+/// `piano::NOTE_NAMES`, so `as usize` gives the pitch class (see
+/// `piano::key_number_to_index`).
+// the variants are the notes they name, and left undocumented so the schema
+// lists them as a plain enum
+#[allow(missing_docs)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, JsonSchema)]
+pub enum PitchClass {
+    #[serde(alias = "Bbb")]
+    A,
+    #[serde(rename = "A#", alias = "Bb")]
+    ASharp,
+    #[serde(alias = "Cb")]
+    B,
+    #[serde(alias = "B#")]
+    C,
+    #[serde(rename = "C#", alias = "Db")]
+    CSharp,
+    D,
+    #[serde(rename = "D#", alias = "Eb")]
+    DSharp,
+    #[serde(alias = "Fb")]
+    E,
+    #[serde(alias = "E#")]
+    F,
+    #[serde(rename = "F#", alias = "Gb")]
+    FSharp,
+    G,
+    #[serde(rename = "G#", alias = "Ab")]
+    GSharp,
+}
+
+/// the scale or chord lit in overlay mode
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, JsonSchema)]
+pub struct Overlay {
+    /// note name, e.g. "C", "F#" or "Bb"
+    pub root: PitchClass,
+    /// which scale or chord, built on `root`
+    #[serde(flatten)]
+    pub notes: Notes,
+    /// flash keys played outside the scale in the warning colour
+    #[serde(default)]
+    pub warn: bool,
+}
+
+impl Default for Overlay {
+    fn default() -> Self {
+        Overlay {
+            root: PitchClass::C,
+            notes: Notes::Scale(Scale::Major),
+            warn: false,
+        }
+    }
+}
+
+impl Overlay {
+    fn contains(&self, root: usize, key_number: usize) -> bool {
+        let interval = (piano::key_number_to_index(key_number) + 12 - root) % 12;
+        self.notes.intervals().contains(&interval)
+    }
+
+    /// Dimly light the keys of the scale under the live levels. Played keys
+    /// outside it are shown in the warning colour while `flash` is on.
+    pub fn show(&self, bins: &mut [f32], flash: bool) -> Vec<Option<KeyColour>> {
+        let mut colours = vec![None; bins.len()];
+        let root = self.root as usize;
+        for (i, level) in bins.iter_mut().enumerate() {
+            if self.contains(root, piano::bin_key_number(i)) {
+                *level = level.max(DIM_LEVEL);
+            } else if self.warn && *level > PLAYED_LEVEL {
+                colours[i] = Some(KeyColour::new(WARNING_HUE, 1.0));
+                if !flash {
+                    *level = 0.0;
+                }
+            }
+        }
+        colours
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pitch_class() {
+        let parse = |name: &str| serde_json::from_value::<PitchClass>(name.into()).ok();
+        assert_eq!(parse("A").map(|p| p as usize), Some(0));
+        assert_eq!(parse("C").map(|p| p as usize), Some(3));
+        assert_eq!(parse("F#").map(|p| p as usize), Some(9));
+        assert_eq!(parse("Bb"), Some(PitchClass::ASharp));
+        assert_eq!(parse("H"), None);
+        assert_eq!(parse("Xq"), None);
+        for (i, name) in piano::NOTE_NAMES.iter().enumerate() {
+            assert_eq!(parse(name).map(|p| p as usize), Some(i));
+        }
+    }
+
+    #[test]
+    fn test_overlay() {
+        let overlay: Overlay =
+            serde_json::from_str(r#"{"root":"C","scale":"major_pentatonic","warn":true}"#).unwrap();
+        let first_key = piano::bin_key_number(0);
+        let mut bins = vec![0.0; piano::num_keys()];
+        // C# played
+        bins[41 - first_key] = 1.0;
+        let colours = overlay.show(&mut bins, true);
+        // C is in the scale, F isn't
+        assert_eq!(bins[40 - first_key], DIM_LEVEL);
+        assert_eq!(bins[45 - first_key], 0.0);
+        assert_eq!(bins[41 - first_key], 1.0);
+        assert!(colours[41 - first_key].is_some());
+
+        let chord: Overlay = serde_json::from_str(r#"{"root":"A","chord":"minor"}"#).unwrap();
+        assert_eq!(chord.notes, Notes::Chord(Quality::Minor));
+    }
+}
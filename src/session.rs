@@ -0,0 +1,292 @@
+use std::collections::BTreeMap;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+
+use crate::analysis::{self, Input};
+use crate::midi_output::{Note, NoteDetector};
+use crate::report;
+
+// a session ends after this long without playing
+const SILENCE: Duration = Duration::from_secs(10);
+// the loudest key has to be above this for the piano to count as playing,
+// as well as the input not being quiet
+const PLAYING_LEVEL: f32 = 0.05;
+const LOUDNESS_BUCKETS: usize = 10;
+// the share of notes the "common range" covers, trimming the extremes
+const COMMON_RANGE: f32 = 0.9;
+/// SESSION_LOG value that writes summaries to stdout instead of a file
+pub const STDOUT: &str = "-";
+
+/// the lowest and highest keys (1-88) played
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Range {
+    lowest: usize,
+    highest: usize,
+}
+
+/// what was played in a session, written out when it ends
+#[derive(Serialize, Debug)]
+pub struct Summary {
+    // seconds since the unix epoch
+    started: u64,
+    duration: f32,
+    playing_time: f32,
+    notes: u32,
+    // notes played on each key number
+    keys: BTreeMap<usize, u32>,
+    // notes by loudness, quietest first
+    loudness: [u32; LOUDNESS_BUCKETS],
+    range: Option<Range>,
+    common_range: Option<Range>,
+}
+
+#[derive(Serialize)]
+struct Report<'a> {
+    session: &'a Summary,
+}
+
+struct Session {
+    started: SystemTime,
+    duration: Duration,
+    playing_time: Duration,
+    keys: BTreeMap<usize, u32>,
+    loudness: [u32; LOUDNESS_BUCKETS],
+}
+
+impl Session {
+    fn summary(&self) -> Summary {
+        let notes = self.keys.values().sum();
+        let range = match (self.keys.keys().next(), self.keys.keys().next_back()) {
+            (Some(&lowest), Some(&highest)) => Some(Range { lowest, highest }),
+            _ => None,
+        };
+        Summary {
+            started: self
+                .started
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs()),
+            duration: self.duration.as_secs_f32(),
+            playing_time: self.playing_time.as_secs_f32(),
+            notes,
+            keys: self.keys.clone(),
+            loudness: self.loudness,
+            range,
+            common_range: common_range(&self.keys, notes),
+        }
+    }
+}
+
+// The keys between which the middle `COMMON_RANGE` of the notes fall.
+fn common_range(keys: &BTreeMap<usize, u32>, notes: u32) -> Option<Range> {
+    if notes == 0 {
+        return None;
+    }
+    let trim = (notes as f32 * (1.0 - COMMON_RANGE) / 2.0).floor() as u32;
+    let mut seen = 0;
+    let mut lowest = None;
+    let mut highest = None;
+    for (&key, &count) in keys {
+        seen += count;
+        if lowest.is_none() && seen > trim {
+            lowest = Some(key);
+        }
+        if highest.is_none() && seen >= notes - trim {
+            highest = Some(key);
+        }
+    }
+    Some(Range {
+        lowest: lowest?,
+        highest: highest?,
+    })
+}
+
+/// Keeps statistics on what's being played, writing a summary as a line of
+/// JSON when the piano has been quiet long enough to end the session.
+pub struct SessionLog {
+    target: String,
+    detector: NoteDetector,
+    session: Option<Session>,
+    quiet_for: Duration,
+}
+
+impl SessionLog {
+    /// `target` is a file to append to, or "-" for stdout
+    pub fn new(target: &str) -> Self {
+        SessionLog {
+            target: target.to_string(),
+            detector: NoteDetector::new(),
+            session: None,
+            quiet_for: Duration::ZERO,
+        }
+    }
+
+    /// Notes are picked out of the levels as `NoteDetector::update` does,
+    /// and quiet audio counts as silence.
+    pub fn update(&mut self, bins: &[f32], input: Input, elapsed: Duration) {
+        let notes = self.detector.update(bins, input);
+        let playing = !input.quiet() && bins.iter().any(|&level| level > PLAYING_LEVEL);
+
+        if self.session.is_none() && notes.iter().any(|n| n.velocity > 0) {
+            self.session = Some(Session {
+                started: SystemTime::now(),
+                duration: Duration::ZERO,
+                playing_time: Duration::ZERO,
+                keys: BTreeMap::new(),
+                loudness: [0; LOUDNESS_BUCKETS],
+            });
+        }
+        let Some(session) = &mut self.session else {
+            return;
+        };
+
+        session.duration += elapsed;
+        for note in notes.iter().filter(|n| n.velocity > 0) {
+            *session.keys.entry(note.key_number).or_default() += 1;
+            session.loudness[loudness_bucket(note, input)] += 1;
+        }
+        if playing {
+            session.playing_time += elapsed;
+            self.quiet_for = Duration::ZERO;
+        } else {
+            self.quiet_for += elapsed;
+        }
+
+        if self.quiet_for >= SILENCE {
+            if let Some(session) = self.session.take() {
+                self.write(&session.summary());
+            }
+            self.quiet_for = Duration::ZERO;
+        }
+    }
+
+    fn write(&self, summary: &Summary) {
+        let message = Report { session: summary };
+        if self.target == STDOUT {
+            report::send(&message);
+            return;
+        }
+        let result = serde_json::to_string(&message)
+            .map_err(std::io::Error::from)
+            .and_then(|json| {
+                let mut file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&self.target)?;
+                writeln!(file, "{}", json)
+            });
+        if let Err(err) = result {
+            report::log(&format!(
+                "failed to write session log {}: {}",
+                self.target, err
+            ));
+        }
+    }
+}
+
+// Audio levels are scaled so the loudest key is always near 1, so how loud
+// a note was comes from the input's RMS, spread in dB from the quiet
+// threshold up to full scale. MIDI notes go by velocity.
+fn loudness_bucket(note: &Note, input: Input) -> usize {
+    let loudness = match input {
+        Input::Audio { rms } => {
+            let floor = 20.0 * analysis::QUIET_RMS.log10();
+            let db = 20.0 * rms.max(analysis::QUIET_RMS).log10();
+            (db - floor) / -floor
+        }
+        _ => (note.velocity.max(1) - 1) as f32 / 127.0,
+    };
+    ((loudness.clamp(0.0, 1.0) * LOUDNESS_BUCKETS as f32) as usize).min(LOUDNESS_BUCKETS - 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::piano;
+
+    const LOUD: Input = Input::Audio { rms: 0.1 };
+    const QUIET: Input = Input::Audio { rms: 0.0 };
+
+    #[test]
+    fn test_common_range() {
+        let keys = BTreeMap::from([(1, 1), (40, 10), (44, 8), (88, 1)]);
+        assert_eq!(
+            common_range(&keys, 20),
+            Some(Range {
+                lowest: 40,
+                highest: 44
+            })
+        );
+        assert_eq!(common_range(&BTreeMap::new(), 0), None);
+    }
+
+    #[test]
+    fn test_session_ends_on_silence() {
+        let mut log = SessionLog::new(STDOUT);
+        let frame = Duration::from_millis(100);
+        let mut bins = vec![0.0; piano::num_keys()];
+        bins[12] = 1.0;
+        log.update(&bins, LOUD, frame);
+        bins[12] = 0.0;
+        log.update(&bins, LOUD, frame);
+        bins[12] = 1.0;
+        log.update(&bins, LOUD, frame);
+
+        let summary = log.session.as_ref().unwrap().summary();
+        assert_eq!(summary.notes, 2);
+        assert_eq!(summary.keys[&piano::bin_key_number(12)], 2);
+        // -20 dBFS is half way between the quiet threshold and full scale
+        assert_eq!(summary.loudness[LOUDNESS_BUCKETS / 2], 2);
+
+        bins.fill(0.0);
+        log.update(&bins, LOUD, SILENCE);
+        assert!(log.session.is_none());
+    }
+
+    #[test]
+    fn test_loudness_buckets() {
+        let note = Note {
+            key_number: 40,
+            velocity: 127,
+        };
+        // the normalised level doesn't matter, the input's RMS does
+        assert_eq!(loudness_bucket(&note, Input::Audio { rms: 0.01 }), 0);
+        assert_eq!(loudness_bucket(&note, Input::Audio { rms: 0.5 }), 8);
+        assert_eq!(loudness_bucket(&note, Input::Audio { rms: 1.0 }), 9);
+
+        assert_eq!(loudness_bucket(&note, Input::Midi), 9);
+        let soft = Note {
+            key_number: 40,
+            velocity: 20,
+        };
+        assert_eq!(loudness_bucket(&soft, Input::Midi), 1);
+    }
+
+    #[test]
+    fn test_soft_midi_notes_count() {
+        let mut log = SessionLog::new(STDOUT);
+        let mut bins = vec![0.0; piano::num_keys()];
+        bins[12] = 20.0 / 127.0;
+        log.update(&bins, Input::Midi, Duration::from_millis(100));
+        let summary = log.session.as_ref().unwrap().summary();
+        assert_eq!(summary.notes, 1);
+        assert_eq!(summary.loudness[1], 1);
+    }
+
+    #[test]
+    fn test_quiet_input_is_silence() {
+        // normalised room noise: the loudest key is always near 1
+        let mut log = SessionLog::new(STDOUT);
+        let mut bins = vec![0.0; piano::num_keys()];
+        bins[12] = 1.0;
+        log.update(&bins, QUIET, Duration::from_millis(100));
+        assert!(log.session.is_none());
+
+        log.update(&bins, LOUD, Duration::from_millis(100));
+        assert!(log.session.is_some());
+        log.update(&bins, QUIET, SILENCE);
+        assert!(log.session.is_none());
+    }
+}
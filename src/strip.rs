@@ -0,0 +1,69 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use smart_leds::RGB8;
+
+use crate::piano::{self, KeyColour, key_colour};
+use crate::{display, envelope};
+
+// How the keys are laid out along the strip, shared by the LED driver and
+// anything that shows what it would, such as the preview.
+
+/// The strip the lights are shown on. `num_leds` and `pin` are only read
+/// when the strip is opened at startup, `offset` takes effect straight away.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, JsonSchema)]
+#[serde(default)]
+pub struct Layout {
+    /// how many LEDs the strip has
+    #[schemars(range(min = 1, max = 1000))]
+    pub num_leds: usize,
+    /// LEDs left dark before the lowest key
+    #[schemars(range(max = 1000))]
+    pub offset: usize,
+    /// GPIO pin the strip's data line is wired to
+    #[schemars(range(min = 0, max = 27))]
+    pub pin: i32,
+}
+
+impl Default for Layout {
+    fn default() -> Self {
+        Layout {
+            num_leds: 144,
+            offset: 5,
+            pin: 10,
+        }
+    }
+}
+
+fn set_colour(data: &mut [RGB8], l: usize, rgb: display::Rgb) {
+    let (r, g, b) = rgb;
+    data[l].r = r;
+    data[l].g = g;
+    data[l].b = b;
+}
+
+/// Work out the colour of every LED on the strip, fading the peaks. White
+/// keys are three LEDs wide, black keys one. Keys that don't fit on `data`
+/// are left off.
+pub fn render(
+    bins: &[f32],
+    colours: &[Option<display::KeyColour>],
+    peak_magnitudes: &mut [f32],
+    config: &display::DisplayConfig,
+    data: &mut [RGB8],
+) {
+    let mut l = config.layout.offset;
+    for (i, &magnitude) in bins.iter().enumerate() {
+        let brightness =
+            envelope::follow(&mut peak_magnitudes[i], magnitude, config.fade) * config.sensitivity;
+        let key = key_colour(piano::bin_key_number(i));
+        let rgb = config.bin_colour(colours, i, key, brightness);
+        let width = match key {
+            KeyColour::White => 3,
+            KeyColour::Black => 1,
+        };
+        for led in l..(l + width).min(data.len()) {
+            set_colour(data, led, rgb);
+        }
+        l += width;
+    }
+}
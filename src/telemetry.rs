@@ -0,0 +1,194 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+use crate::analysis;
+use crate::control::State;
+use crate::display::DisplayConfig;
+use crate::report;
+
+/// Collects how the pipeline is doing between status messages, so the Elixir
+/// side can show the health of the lights and notice when they stall.
+pub struct Telemetry {
+    interval: Duration,
+    since: Instant,
+    frames: u32,
+    busy: Duration,
+    slowest: Duration,
+    input_frames: u32,
+    rms: f32,
+    peak: f32,
+}
+
+/// how long frames took to draw over an interval
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Latency {
+    /// the average
+    pub mean_ms: f32,
+    /// the slowest
+    pub max_ms: f32,
+}
+
+/// how loud the audio input was over an interval
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Level {
+    /// mean RMS of the analysed windows, 0-1
+    pub rms: f32,
+    /// the largest sample, 0-1
+    pub peak: f32,
+}
+
+/// a summary of one interval, sent as `{"telemetry":{...}}`
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Status {
+    /// frames drawn per second
+    pub fps: f32,
+    /// time spent drawing each frame, analysis included
+    pub latency: Latency,
+    /// audio overruns since the start, absent without an audio source
+    pub overruns: Option<u64>,
+    /// the audio level, absent without any audio this interval
+    pub input: Option<Level>,
+    /// key numbers (1-88) currently sounding, as the note detector sees them,
+    /// so none while the input is quiet
+    pub notes: Vec<usize>,
+    /// changes whenever the config does, to spot configs that didn't apply
+    pub config_hash: String,
+    /// what the display loop is doing
+    #[serde(flatten)]
+    pub state: State,
+}
+
+#[derive(Serialize)]
+struct Message<'a> {
+    telemetry: &'a Status,
+}
+
+impl Telemetry {
+    /// Report every `interval`, starting now.
+    pub fn new(interval: Duration) -> Self {
+        Telemetry {
+            interval,
+            since: Instant::now(),
+            frames: 0,
+            busy: Duration::ZERO,
+            slowest: Duration::ZERO,
+            input_frames: 0,
+            rms: 0.0,
+            peak: 0.0,
+        }
+    }
+
+    /// count a frame that took `took` to draw
+    pub fn frame(&mut self, took: Duration) {
+        self.frames += 1;
+        self.busy += took;
+        self.slowest = self.slowest.max(took);
+    }
+
+    /// the samples analysed this frame
+    pub fn input(&mut self, samples: &[f32]) {
+        if samples.is_empty() {
+            return;
+        }
+        self.rms += analysis::rms(samples);
+        let peak = samples.iter().fold(0.0f32, |peak, s| peak.max(s.abs()));
+        self.peak = self.peak.max(peak);
+        self.input_frames += 1;
+    }
+
+    /// Has the interval run out, so it's time to call `status`?
+    pub fn due(&self) -> bool {
+        self.since.elapsed() >= self.interval
+    }
+
+    /// Sum up the interval so far and start a new one. `overruns`, `notes`
+    /// and `state` come from the pipeline as they stand now.
+    pub fn status(
+        &mut self,
+        config: &DisplayConfig,
+        overruns: Option<u64>,
+        notes: Vec<usize>,
+        state: State,
+    ) -> Status {
+        let elapsed = self.since.elapsed().as_secs_f32();
+        let frames = self.frames.max(1);
+        let status = Status {
+            fps: if elapsed > 0.0 {
+                self.frames as f32 / elapsed
+            } else {
+                0.0
+            },
+            latency: Latency {
+                mean_ms: self.busy.as_secs_f32() * 1000.0 / frames as f32,
+                max_ms: self.slowest.as_secs_f32() * 1000.0,
+            },
+            overruns,
+            input: (self.input_frames > 0).then(|| Level {
+                rms: self.rms / self.input_frames as f32,
+                peak: self.peak,
+            }),
+            notes,
+            config_hash: config_hash(config),
+            state,
+        };
+        *self = Telemetry::new(self.interval);
+        status
+    }
+}
+
+impl Status {
+    /// Send the status to the Elixir side, see `report::send`.
+    pub fn send(&self) {
+        report::send(&Message { telemetry: self });
+    }
+}
+
+/// Changes whenever the active config does, so a client can tell whether
+/// its last update took.
+pub fn config_hash(config: &DisplayConfig) -> String {
+    let mut hasher = DefaultHasher::new();
+    serde_json::to_string(config)
+        .unwrap_or_default()
+        .hash(&mut hasher);
+    format!("{:016x}", hasher.finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_status() {
+        let mut telemetry = Telemetry::new(Duration::ZERO);
+        telemetry.frame(Duration::from_millis(2));
+        telemetry.frame(Duration::from_millis(4));
+        telemetry.input(&[0.5, -0.5, 0.5, -0.5]);
+        telemetry.input(&[0.0, -1.0]);
+        assert!(telemetry.due());
+
+        let config = DisplayConfig::default();
+        let status = telemetry.status(&config, Some(3), vec![49], State::Live);
+        assert!((status.latency.mean_ms - 3.0).abs() < 1e-3);
+        assert!((status.latency.max_ms - 4.0).abs() < 1e-3);
+        let input = status.input.unwrap();
+        assert!((input.rms - (0.5 + 0.5f32.sqrt()) / 2.0).abs() < 1e-6);
+        assert_eq!(input.peak, 1.0);
+        assert_eq!(status.config_hash, config_hash(&config));
+
+        // the next interval starts from nothing
+        let status = telemetry.status(&config, None, Vec::new(), State::Live);
+        assert!(status.input.is_none());
+        assert_eq!(status.latency.max_ms, 0.0);
+    }
+
+    #[test]
+    fn test_config_hash_changes() {
+        let mut config = DisplayConfig::default();
+        let before = config_hash(&config);
+        config.fade = 0.5;
+        assert_ne!(config_hash(&config), before);
+    }
+}
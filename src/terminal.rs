@@ -1,50 +1,44 @@
-#![allow(dead_code)]
+use crate::{display, envelope};
 
-use crate::display;
-
-use crate::piano::{key_colour, KeyColour};
+use crate::piano::{self, key_colour};
 
+/// A display that prints the strip's colours to the terminal, for trying
+/// things out away from the lights.
 pub struct Terminal {}
 
 impl Terminal {
+    /// A display for the terminal on stdout.
     pub fn new() -> Self {
         Terminal {}
     }
 }
 
+impl Default for Terminal {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl display::Display for Terminal {
     fn visualize_bins(
         &mut self,
         bins: &[f32],
+        colours: &[Option<display::KeyColour>],
         peak_magnitudes: &mut Vec<f32>,
         config: &display::DisplayConfig,
     ) {
         let mut lights: Vec<String> = Vec::with_capacity(bins.len());
 
         for (i, &magnitude) in bins.iter().enumerate() {
-            let key_colour = key_colour(i + 1);
-            if magnitude > peak_magnitudes[i] {
-                peak_magnitudes[i] = magnitude;
-            } else {
-                peak_magnitudes[i] *= config.fade;
-            }
-
-            let brightness = peak_magnitudes[i];
+            let key_colour = key_colour(piano::bin_key_number(i));
+            let brightness = envelope::follow(&mut peak_magnitudes[i], magnitude, config.fade);
             // let brightness = 255.0;
             // let character = "●";
             let character = "█";
             // let character = "■";
 
-            let colour = match key_colour {
-                KeyColour::Black => {
-                    let (r, g, b) = config.black_colour(brightness);
-                    format!("{};{};{}", r, g, b)
-                }
-                KeyColour::White => {
-                    let (r, g, b) = config.white_colour(brightness);
-                    format!("{};{};{}", r, g, b)
-                }
-            };
+            let (r, g, b) = config.bin_colour(colours, i, key_colour, brightness);
+            let colour = format!("{};{};{}", r, g, b);
             lights.push(format!(
                 // "\x1B[38;2;{0};{0};0m{1}\x1B[0m",
                 // "\x1B[38;2;{0};{0};0m{1}\x1B[0m",
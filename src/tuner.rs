@@ -0,0 +1,148 @@
+use spectrum_analyzer::FrequencySpectrum;
+
+use crate::display::KeyColour;
+use crate::piano;
+
+// the peak has to stand this far above the average of the spectrum to count
+// as a pitch
+const MIN_PROMINENCE: f32 = 3.0;
+// deviation at which the colour is fully red and the bar fully extended
+const MAX_CENTS: f32 = 50.0;
+// how many keys either side of the note the deviation bar can reach
+const BAR_KEYS: usize = 3;
+// brightness of the deviation bar relative to the centre marker
+const BAR_LEVEL: f32 = 0.4;
+
+/// The pitch of the loudest note, for tuner mode.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Pitch {
+    /// in Hz
+    pub frequency: f32,
+    /// the nearest key (1-88)
+    pub key_number: usize,
+    /// how far the pitch is from the key's equal-tempered frequency, negative
+    /// is flat
+    pub cents: f32,
+}
+
+impl Pitch {
+    /// The nearest key to `frequency` in Hz, and how far off it is.
+    pub fn from_frequency(frequency: f32) -> Self {
+        let position = piano::frequency_to_key_number(frequency);
+        let key_number = position.round() as usize;
+        Pitch {
+            frequency,
+            key_number,
+            cents: (position - key_number as f32) * 100.0,
+        }
+    }
+}
+
+/// The dominant pitch in the spectrum, located between FFT bins by fitting a
+/// parabola through the peak and its neighbours.
+pub fn detect(spectrum: &FrequencySpectrum) -> Option<Pitch> {
+    let points: Vec<(f32, f32)> = spectrum
+        .data()
+        .iter()
+        .map(|(freq, value)| (freq.val(), value.val()))
+        .collect();
+    find_peak(&points).map(Pitch::from_frequency)
+}
+
+fn find_peak(points: &[(f32, f32)]) -> Option<f32> {
+    if points.len() < 3 {
+        return None;
+    }
+    let mean = points.iter().map(|(_, v)| v).sum::<f32>() / points.len() as f32;
+    let (k, &(freq, peak)) = points[1..points.len() - 1]
+        .iter()
+        .enumerate()
+        .max_by(|(_, a), (_, b)| a.1.total_cmp(&b.1))
+        .map(|(i, p)| (i + 1, p))?;
+    if peak <= 0.0 || peak < mean * MIN_PROMINENCE {
+        return None;
+    }
+
+    let before = points[k - 1].1;
+    let after = points[k + 1].1;
+    let denominator = before - 2.0 * peak + after;
+    let shift = if denominator.abs() > f32::EPSILON {
+        (0.5 * (before - after) / denominator).clamp(-0.5, 0.5)
+    } else {
+        0.0
+    };
+    let step = points[k + 1].0 - freq;
+    Some(freq + shift * step)
+}
+
+/// Draw the tuner into the bins: the detected key is the centre marker and
+/// the keys to its sharp or flat side show how far out it is. Returns the
+/// per-bin colours, green when in tune fading to red at `MAX_CENTS`.
+pub fn show(pitch: Option<Pitch>, bins: &mut [f32]) -> Vec<Option<KeyColour>> {
+    bins.fill(0.0);
+    let mut colours = vec![None; bins.len()];
+    let Some(pitch) = pitch else {
+        return colours;
+    };
+    let Some(centre) = pitch.key_number.checked_sub(piano::bin_key_number(0)) else {
+        return colours;
+    };
+    if centre >= bins.len() {
+        return colours;
+    }
+
+    let deviation = (pitch.cents.abs() / MAX_CENTS).min(1.0);
+    let colour = KeyColour::new(120.0 * (1.0 - deviation), 1.0);
+    bins[centre] = 1.0;
+    colours[centre] = Some(colour.clone());
+
+    let bar = (deviation * BAR_KEYS as f32).ceil() as usize;
+    for n in 1..=bar {
+        let bin = if pitch.cents > 0.0 {
+            centre.checked_add(n)
+        } else {
+            centre.checked_sub(n)
+        };
+        if let Some(bin) = bin.filter(|&b| b < bins.len()) {
+            bins[bin] = BAR_LEVEL;
+            colours[bin] = Some(colour.clone());
+        }
+    }
+    colours
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_peak_interpolates() {
+        // a peak between 440 and 445, nearer 440
+        let mut points: Vec<(f32, f32)> = (0..20).map(|i| (400.0 + i as f32 * 5.0, 0.0)).collect();
+        points[7].1 = 0.1;
+        points[8].1 = 1.0;
+        points[9].1 = 0.6;
+        let freq = find_peak(&points).unwrap();
+        assert!(freq > 440.0 && freq < 442.5, "freq {}", freq);
+
+        assert_eq!(find_peak(&[(430.0, 0.5), (435.0, 0.5), (440.0, 0.5)]), None);
+    }
+
+    #[test]
+    fn test_show_deviation() {
+        let pitch = Pitch::from_frequency(440.0 * 2.0_f32.powf(20.0 / 1200.0));
+        assert_eq!(pitch.key_number, 49);
+        assert!((pitch.cents - 20.0).abs() < 0.1);
+
+        let mut bins = vec![0.0; piano::num_keys()];
+        let colours = show(Some(pitch), &mut bins);
+        let centre = 49 - piano::bin_key_number(0);
+        assert_eq!(bins[centre], 1.0);
+        // 20 cents sharp lights two keys above, none below
+        assert_eq!(bins[centre + 1], BAR_LEVEL);
+        assert_eq!(bins[centre + 2], BAR_LEVEL);
+        assert_eq!(bins[centre + 3], 0.0);
+        assert_eq!(bins[centre - 1], 0.0);
+        assert!(colours[centre].is_some());
+    }
+}
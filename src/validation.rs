@@ -0,0 +1,400 @@
+use std::sync::OnceLock;
+
+use serde_json::{Value, json};
+
+use crate::control::Command;
+use crate::display::DisplayConfig;
+use crate::playback;
+
+// Range checks driven by the JSON Schema generated from the config and
+// command types. The ranges are declared next to each field with schemars
+// attributes (`range`, `exclusiveMinimum`, `exclusiveMaximum`) and numbers
+// outside them are rejected, unless the field has an `x-clamp` rule:
+//
+// - "clamp": pulled back to the nearest end of the range
+// - "wrap": wrapped round into the range, for angles
+
+/// a value that is out of range and can't be fixed up
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Violation {
+    pub field: String,
+    pub message: String,
+}
+
+/// a value that was clamped or wrapped into range
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Adjustment {
+    pub field: String,
+    pub from: f64,
+    pub to: f64,
+}
+
+fn generate<T: schemars::JsonSchema>() -> Value {
+    schemars::schema_for!(T).to_value()
+}
+
+pub(crate) fn config_schema() -> &'static Value {
+    static SCHEMA: OnceLock<Value> = OnceLock::new();
+    SCHEMA.get_or_init(generate::<DisplayConfig>)
+}
+
+pub(crate) fn command_schema() -> &'static Value {
+    static SCHEMA: OnceLock<Value> = OnceLock::new();
+    SCHEMA.get_or_init(generate::<Command>)
+}
+
+pub(crate) fn playback_schema() -> &'static Value {
+    static SCHEMA: OnceLock<Value> = OnceLock::new();
+    SCHEMA.get_or_init(generate::<playback::Message>)
+}
+
+/// Everything a client can send, for building and checking controls. A
+/// config line may also leave fields out, see `protocol::apply`.
+pub fn protocol_schema() -> Value {
+    json!({
+        "config": config_schema(),
+        "command": command_schema(),
+        "playback": playback_schema(),
+    })
+}
+
+/// Check every number in `value` against `schema`, fixing up the ones that
+/// can be. Anything that doesn't fit the schema's shape is left for serde
+/// to report.
+pub(crate) fn validate(schema: &Value, value: &mut Value) -> Result<Vec<Adjustment>, Violation> {
+    let mut adjustments = Vec::new();
+    check(schema, schema, value, "", &mut adjustments)?;
+    Ok(adjustments)
+}
+
+fn check(
+    root: &Value,
+    schema: &Value,
+    value: &mut Value,
+    path: &str,
+    adjustments: &mut Vec<Adjustment>,
+) -> Result<(), Violation> {
+    let schema = resolve(root, schema);
+    if !schema.is_object() {
+        return Ok(());
+    }
+    for sub in list(schema, "allOf") {
+        check(root, sub, value, path, adjustments)?;
+    }
+    // only the variants that could be this value, e.g. the one whose tag
+    // matches for a tagged enum
+    for key in ["oneOf", "anyOf"] {
+        for sub in list(schema, key) {
+            if matches(root, sub, value) {
+                check(root, sub, value, path, adjustments)?;
+            }
+        }
+    }
+
+    match value {
+        Value::Number(number) => {
+            let Some(from) = number.as_f64() else {
+                return Ok(());
+            };
+            let to = in_range(schema, from, path)?;
+            if to != from {
+                adjustments.push(Adjustment {
+                    field: path.to_string(),
+                    from,
+                    to,
+                });
+                *value = json!(to);
+            }
+        }
+        Value::Object(object) => {
+            if let Some(Value::Object(properties)) = schema.get("properties") {
+                for (key, sub) in properties {
+                    if let Some(value) = object.get_mut(key) {
+                        let path = if path.is_empty() {
+                            key.clone()
+                        } else {
+                            format!("{}.{}", path, key)
+                        };
+                        check(root, sub, value, &path, adjustments)?;
+                    }
+                }
+            }
+        }
+        Value::Array(values) => {
+            if let Some(items) = schema.get("items") {
+                for (i, value) in values.iter_mut().enumerate() {
+                    let path = format!("{}[{}]", path, i);
+                    check(root, items, value, &path, adjustments)?;
+                }
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Reject any key in `value` that `schema` doesn't know, such as a
+/// misspelt field, which serde would otherwise quietly ignore. Objects whose
+/// schema lists no properties, or an enum variant that can't be picked out,
+/// are left for serde.
+pub(crate) fn check_fields(schema: &Value, value: &Value) -> Result<(), Violation> {
+    fields(schema, schema, value, "")
+}
+
+fn fields(root: &Value, schema: &Value, value: &Value, path: &str) -> Result<(), Violation> {
+    match value {
+        Value::Object(object) => {
+            let mut known = Vec::new();
+            properties(root, schema, value, &mut known);
+            if known.is_empty() {
+                return Ok(());
+            }
+            for (key, value) in object {
+                let path = if path.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{}.{}", path, key)
+                };
+                let Some((_, sub)) = known.iter().find(|(name, _)| *name == key) else {
+                    return Err(Violation {
+                        message: format!("{} isn't a known field", path),
+                        field: path,
+                    });
+                };
+                fields(root, sub, value, &path)?;
+            }
+        }
+        Value::Array(values) => {
+            if let Some(items) = resolve(root, schema).get("items") {
+                for (i, value) in values.iter().enumerate() {
+                    fields(root, items, value, &format!("{}[{}]", path, i))?;
+                }
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+// every property `schema` allows on `value`, with its schema, including
+// those from the variants `value` could be
+fn properties<'a>(
+    root: &'a Value,
+    schema: &'a Value,
+    value: &Value,
+    known: &mut Vec<(&'a String, &'a Value)>,
+) {
+    let schema = resolve(root, schema);
+    if let Some(Value::Object(properties)) = schema.get("properties") {
+        known.extend(properties);
+    }
+    for sub in list(schema, "allOf") {
+        properties(root, sub, value, known);
+    }
+    for key in ["oneOf", "anyOf"] {
+        for sub in list(schema, key) {
+            if matches(root, sub, value) {
+                properties(root, sub, value, known);
+            }
+        }
+    }
+}
+
+// the value to use for `number`, or why it can't be used
+fn in_range(schema: &Value, number: f64, field: &str) -> Result<f64, Violation> {
+    let bound = |key| schema.get(key).and_then(Value::as_f64);
+    let min = bound("minimum");
+    let max = bound("maximum");
+    let above = bound("exclusiveMinimum");
+    let below = bound("exclusiveMaximum");
+    let fits = min.is_none_or(|min| number >= min)
+        && max.is_none_or(|max| number <= max)
+        && above.is_none_or(|above| number > above)
+        && below.is_none_or(|below| number < below);
+    if fits {
+        return Ok(number);
+    }
+
+    match (schema.get("x-clamp").and_then(Value::as_str), min, max) {
+        (Some("clamp"), Some(min), Some(max)) => return Ok(number.clamp(min, max)),
+        (Some("wrap"), Some(min), Some(max)) if max > min => {
+            return Ok((number - min).rem_euclid(max - min) + min);
+        }
+        _ => {}
+    }
+
+    let mut limits = Vec::new();
+    if let Some(min) = min {
+        limits.push(format!("at least {}", min));
+    }
+    if let Some(above) = above {
+        limits.push(format!("more than {}", above));
+    }
+    if let Some(max) = max {
+        limits.push(format!("at most {}", max));
+    }
+    if let Some(below) = below {
+        limits.push(format!("less than {}", below));
+    }
+    Err(Violation {
+        field: field.to_string(),
+        message: format!("{} must be {}, got {}", field, limits.join(" and "), number),
+    })
+}
+
+// Could `value` be an instance of `schema`? Only the type and any constant
+// properties are looked at, which is enough to pick out enum variants.
+fn matches(root: &Value, schema: &Value, value: &Value) -> bool {
+    let schema = resolve(root, schema);
+    if let Some(expected) = schema.get("type") {
+        let types = match expected {
+            Value::Array(types) => types.iter().filter_map(Value::as_str).collect(),
+            other => other.as_str().into_iter().collect::<Vec<_>>(),
+        };
+        if !types.iter().any(|&t| is_type(value, t)) {
+            return false;
+        }
+    }
+    if let Some(constant) = schema.get("const") {
+        return constant == value;
+    }
+    if let (Some(Value::Object(properties)), Value::Object(object)) =
+        (schema.get("properties"), value)
+    {
+        for (key, sub) in properties {
+            let sub = resolve(root, sub);
+            if let Some(constant) = sub.get("const")
+                && object.get(key) != Some(constant)
+            {
+                return false;
+            }
+        }
+    }
+    true
+}
+
+fn is_type(value: &Value, name: &str) -> bool {
+    match name {
+        "null" => value.is_null(),
+        "boolean" => value.is_boolean(),
+        "integer" => value.is_i64() || value.is_u64(),
+        "number" => value.is_number(),
+        "string" => value.is_string(),
+        "array" => value.is_array(),
+        "object" => value.is_object(),
+        _ => true,
+    }
+}
+
+// follow a local `$ref` such as "#/$defs/KeyColour"
+fn resolve<'a>(root: &'a Value, schema: &'a Value) -> &'a Value {
+    match schema.get("$ref").and_then(Value::as_str) {
+        Some(reference) => reference
+            .strip_prefix('#')
+            .and_then(|pointer| root.pointer(pointer))
+            .unwrap_or(schema),
+        None => schema,
+    }
+}
+
+fn list<'a>(schema: &'a Value, key: &str) -> &'a [Value] {
+    schema
+        .get(key)
+        .and_then(Value::as_array)
+        .map_or(&[], Vec::as_slice)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> Value {
+        serde_json::to_value(DisplayConfig::default()).unwrap()
+    }
+
+    #[test]
+    fn test_default_config_is_valid() {
+        let mut value = config();
+        assert_eq!(validate(config_schema(), &mut value), Ok(Vec::new()));
+    }
+
+    #[test]
+    fn test_reject() {
+        let mut value = config();
+        value["fade"] = json!(1.0);
+        let violation = validate(config_schema(), &mut value).unwrap_err();
+        assert_eq!(violation.field, "fade");
+        assert!(
+            violation.message.contains("less than 1"),
+            "{}",
+            violation.message
+        );
+
+        let mut value = config();
+        value["decay"] = json!(0.0);
+        assert!(validate(config_schema(), &mut value).is_err());
+    }
+
+    #[test]
+    fn test_clamp_and_wrap() {
+        let mut value = config();
+        value["brightness"] = json!(1.5);
+        value["white"]["hue"] = json!(370.0);
+        value["black"]["saturation"] = json!(-0.5);
+        let adjustments = validate(config_schema(), &mut value).unwrap();
+        assert_eq!(adjustments.len(), 3);
+        assert_eq!(value["brightness"], 1.0);
+        assert_eq!(value["white"]["hue"], 10.0);
+        assert_eq!(value["black"]["saturation"], 0.0);
+    }
+
+    #[test]
+    fn test_enum_variants() {
+        let mut value = config();
+        value["weighting"] = json!({"type": "gaussian", "sigma": -1.0});
+        let violation = validate(config_schema(), &mut value).unwrap_err();
+        assert_eq!(violation.field, "weighting.sigma");
+
+        value["weighting"] = json!({"type": "gaussian", "sigma": 1.0});
+        value["equalisation"] = json!({"type": "keys", "gains": [1.0, -2.0]});
+        let violation = validate(config_schema(), &mut value).unwrap_err();
+        assert_eq!(violation.field, "equalisation.gains[1]");
+    }
+
+    #[test]
+    fn test_unknown_fields() {
+        let mut value = config();
+        assert_eq!(check_fields(config_schema(), &value), Ok(()));
+
+        value["brightnes"] = json!(0.5);
+        let violation = check_fields(config_schema(), &value).unwrap_err();
+        assert_eq!(violation.field, "brightnes");
+
+        // nested, in an enum variant and in a flattened one
+        let mut value = config();
+        value["white"]["hu"] = json!(10.0);
+        assert_eq!(
+            check_fields(config_schema(), &value).unwrap_err().field,
+            "white.hu"
+        );
+        let mut value = config();
+        value["weighting"] = json!({"type": "gaussian", "sigma": 1.0, "steepness": 2.0});
+        let violation = check_fields(config_schema(), &value).unwrap_err();
+        assert_eq!(violation.field, "weighting.steepness");
+        let mut value = config();
+        value["overlay"] = json!({"root": "D", "chord": "minor7", "warn": true});
+        assert_eq!(check_fields(config_schema(), &value), Ok(()));
+        value["overlay"]["scal"] = json!("dorian");
+        let violation = check_fields(config_schema(), &value).unwrap_err();
+        assert_eq!(violation.field, "overlay.scal");
+    }
+
+    #[test]
+    fn test_command() {
+        let mut value = json!({"command": "identify", "key": 89});
+        let violation = validate(command_schema(), &mut value).unwrap_err();
+        assert_eq!(violation.field, "key");
+        let mut value = json!({"command": "identify", "key": 49});
+        assert!(validate(command_schema(), &mut value).is_ok());
+    }
+}
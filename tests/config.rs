@@ -0,0 +1,142 @@
+// Configs stored by earlier versions, e.g. in the Elixir preset store, have
+// to keep loading as the config grows.
+use serde_json::{Value, json};
+
+use leds::display::{CONFIG_VERSION, DisplayConfig};
+use leds::protocol::{self, ErrorKind, Request};
+
+fn load(stored: Value) -> Value {
+    let config =
+        protocol::load(&stored).unwrap_or_else(|err| panic!("{} didn't load: {:?}", stored, err));
+    serde_json::to_value(config).unwrap()
+}
+
+// the config before any of the analysis options, as the Elixir side still
+// sends it. values are ones an f32 holds exactly, so they compare equal
+// after loading
+fn baseline() -> Value {
+    json!({
+        "white": {"hue": 1.0, "saturation": 0.875},
+        "black": {"hue": 350.0, "saturation": 0.875},
+        "fade": 0.875,
+        "brightness": 0.75,
+        "sensitivity": 1.0,
+        "decay": 1.75,
+        "scale": false,
+    })
+}
+
+// baseline plus the fields added by later changes, in the order they landed
+fn unversioned() -> Vec<Value> {
+    let additions = [
+        json!({"weighting": {"type": "gaussian", "sigma": 1.5}}),
+        json!({"equalisation": {"type": "bass_lift", "amount": 2.0}}),
+        json!({"mode": "chroma"}),
+        json!({"chord_colour": "quality"}),
+        json!({"key_palette": true, "out_of_key": {"hue": 180.0, "saturation": 0.5}}),
+        json!({"beat_effect": {"type": "pulse", "amount": 0.5}}),
+        json!({"mode": "tuner"}),
+        json!({"mode": "lesson"}),
+        json!({"mode": "overlay", "overlay": {"root": "D", "scale": "dorian", "warn": true}}),
+    ];
+    let mut stored = baseline();
+    let mut shapes = vec![stored.clone()];
+    for addition in additions {
+        for (key, value) in addition.as_object().unwrap() {
+            stored[key] = value.clone();
+        }
+        shapes.push(stored.clone());
+    }
+    shapes
+}
+
+#[test]
+fn unversioned_configs_load() {
+    for stored in unversioned() {
+        let config = load(stored.clone());
+        assert_eq!(config["version"], CONFIG_VERSION);
+        for (key, value) in stored.as_object().unwrap() {
+            assert_eq!(&config[key], value, "{} changed loading {}", key, stored);
+        }
+    }
+}
+
+#[test]
+fn missing_fields_get_defaults() {
+    let config = load(baseline());
+    let defaults = serde_json::to_value(DisplayConfig::default()).unwrap();
+    for key in [
+        "weighting",
+        "equalisation",
+        "mode",
+        "overlay",
+        "beat_effect",
+    ] {
+        assert_eq!(config[key], defaults[key]);
+    }
+    assert_eq!(config["brightness"], 0.75);
+}
+
+#[test]
+fn unversioned_out_of_range_values_are_reset() {
+    let mut stored = baseline();
+    stored["fade"] = json!(1.0);
+    stored["decay"] = json!(0.0);
+    let config = load(stored);
+    assert_eq!(config["fade"], 0.9f32 as f64);
+    assert_eq!(config["decay"], 1.8f32 as f64);
+}
+
+#[test]
+fn current_version_is_not_migrated() {
+    let mut stored = serde_json::to_value(DisplayConfig::default()).unwrap();
+    assert_eq!(stored["version"], CONFIG_VERSION);
+    assert_eq!(load(stored.clone()), stored);
+
+    // a current config has no excuse for a fade of 1
+    stored["fade"] = json!(1.0);
+    let err = protocol::load(&stored).unwrap_err();
+    assert_eq!(err.field.as_deref(), Some("fade"));
+}
+
+#[test]
+fn unversioned_configs_on_stdin_load_like_stored_ones() {
+    // the Elixir side sends its whole config, with no version, on every
+    // change, so it has to come out the same as at startup
+    let mut stored = baseline();
+    stored["fade"] = json!(1.0);
+    let line = stored.to_string();
+    let patch = match protocol::parse(&line).unwrap() {
+        Request::Config(patch) => patch,
+        other => panic!("expected a config, got {:?}", other),
+    };
+    let mut current = DisplayConfig::default();
+    current.brightness = 0.25;
+    let live = protocol::apply(&current, &patch).unwrap();
+    assert_eq!(live, protocol::load(&stored).unwrap());
+    assert_eq!(live.fade, DisplayConfig::default().fade);
+}
+
+#[test]
+fn patches_are_only_migrated_when_they_name_a_version() {
+    // a live update is for the current version, so a bad fade is an error
+    // rather than quietly reset
+    let patch = json!({"fade": 1.0});
+    let err = protocol::apply(&DisplayConfig::default(), &patch).unwrap_err();
+    assert_eq!(err.field.as_deref(), Some("fade"));
+
+    let patch = json!({"version": 0, "fade": 1.0});
+    let config = protocol::apply(&DisplayConfig::default(), &patch).unwrap();
+    assert_eq!(config.fade, DisplayConfig::default().fade);
+}
+
+#[test]
+fn newer_versions_are_rejected() {
+    for version in [json!(CONFIG_VERSION + 1), json!("1"), json!(-1)] {
+        let mut stored = baseline();
+        stored["version"] = version;
+        let err = protocol::load(&stored).unwrap_err();
+        assert_eq!(err.kind, ErrorKind::Invalid);
+        assert_eq!(err.field.as_deref(), Some("version"));
+    }
+}
@@ -0,0 +1,128 @@
+// Golden-output tests: synthetic tones go through the whole analysis and
+// display path and the resulting key levels and LED colours are compared
+// with frames stored in tests/golden. Run with UPDATE_GOLDEN=1 to rewrite
+// them after an intentional change.
+use std::f32::consts::PI;
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+use smart_leds::RGB8;
+
+use leds::analysis::{SAMPLE_SIZE, analyse};
+use leds::display::DisplayConfig;
+use leds::piano::{self, Weighting};
+use leds::strip::render;
+
+const SAMPLE_RATE: u32 = 48_000;
+const AMPLITUDE: f32 = 0.3;
+// allowed difference in key levels and colour channels from the golden
+// frame, for floating point noise
+const LEVEL_TOLERANCE: f32 = 1e-3;
+const COLOUR_TOLERANCE: u8 = 1;
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Golden {
+    // per-key levels after fading, as shown on the strip
+    levels: Vec<f32>,
+    leds: Vec<(u8, u8, u8)>,
+}
+
+fn tone(key_numbers: &[usize]) -> Vec<f32> {
+    (0..SAMPLE_SIZE)
+        .map(|n| {
+            let t = n as f32 / SAMPLE_RATE as f32;
+            key_numbers
+                .iter()
+                .map(|&k| AMPLITUDE * (2.0 * PI * piano::key_number_to_frequency(k) * t).sin())
+                .sum()
+        })
+        .collect()
+}
+
+// Feed each block of samples through as one frame and return the final
+// frame.
+fn run(frames: &[Vec<f32>], config: &DisplayConfig) -> Golden {
+    let num_bins = piano::num_keys();
+    let mut bins = vec![0.0; num_bins];
+    let mut peak_magnitudes = vec![0.0; num_bins];
+    let mut data = vec![RGB8::default(); config.layout.num_leds];
+    for samples in frames {
+        analyse(samples, SAMPLE_RATE, &mut bins, config);
+        render(&bins, &[], &mut peak_magnitudes, config, &mut data);
+    }
+    Golden {
+        levels: peak_magnitudes
+            .iter()
+            .map(|p| (p * config.sensitivity * 1000.0).round() / 1000.0)
+            .collect(),
+        leds: data.iter().map(|c| (c.r, c.g, c.b)).collect(),
+    }
+}
+
+fn check(name: &str, frames: &[Vec<f32>], config: &DisplayConfig) {
+    let actual = run(frames, config);
+    let path: PathBuf = [env!("CARGO_MANIFEST_DIR"), "tests", "golden", name]
+        .iter()
+        .collect::<PathBuf>()
+        .with_extension("json");
+
+    if std::env::var("UPDATE_GOLDEN").is_ok() {
+        let json = serde_json::to_string_pretty(&actual).unwrap();
+        std::fs::write(&path, json + "\n").unwrap();
+        return;
+    }
+
+    let json = std::fs::read_to_string(&path)
+        .unwrap_or_else(|e| panic!("missing golden frame {:?}: {}", path, e));
+    let expected: Golden = serde_json::from_str(&json).unwrap();
+    for (key, (a, e)) in actual.levels.iter().zip(&expected.levels).enumerate() {
+        assert!(
+            (a - e).abs() <= LEVEL_TOLERANCE,
+            "{}: level of bin {} is {}, expected {}",
+            name,
+            key,
+            a,
+            e
+        );
+    }
+    for (led, (a, e)) in actual.leds.iter().zip(&expected.leds).enumerate() {
+        let close = a.0.abs_diff(e.0) <= COLOUR_TOLERANCE
+            && a.1.abs_diff(e.1) <= COLOUR_TOLERANCE
+            && a.2.abs_diff(e.2) <= COLOUR_TOLERANCE;
+        assert!(close, "{}: led {} is {:?}, expected {:?}", name, led, a, e);
+    }
+    assert_eq!(actual.levels.len(), expected.levels.len());
+    assert_eq!(actual.leds.len(), expected.leds.len());
+}
+
+// with output scaling on, so levels are relative to the loudest key
+fn scaled() -> DisplayConfig {
+    let mut config = DisplayConfig::default();
+    config.scale = true;
+    config
+}
+
+#[test]
+fn golden_single_note() {
+    // A4
+    check("a4", &[tone(&[49])], &scaled());
+    check("a4_default", &[tone(&[49])], &DisplayConfig::default());
+    let mut triangular = scaled();
+    triangular.weighting = Weighting::Triangular;
+    check("a4_triangular", &[tone(&[49])], &triangular);
+}
+
+#[test]
+fn golden_chord() {
+    // C4 E4 G4
+    check("c_major", &[tone(&[40, 44, 47])], &scaled());
+}
+
+#[test]
+fn golden_fade() {
+    // A4 then ten frames of silence
+    let mut frames = vec![tone(&[49])];
+    frames.extend(std::iter::repeat_n(vec![0.0; SAMPLE_SIZE], 10));
+    check("a4_release", &frames, &scaled());
+    check("a4_release_default", &frames, &DisplayConfig::default());
+}
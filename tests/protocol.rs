@@ -0,0 +1,435 @@
+// Tests for the stdin protocol: every kind of bad line should come back as
+// an error naming what was wrong rather than stopping the process.
+use std::io::Cursor;
+use std::sync::{Arc, Mutex};
+
+use leds::control::{Command, State};
+use leds::display::DisplayConfig;
+use leds::etf;
+use leds::midi::Keyboard;
+use leds::null::Null;
+use leds::piano::Weighting;
+use leds::pipeline::{Pipeline, Source};
+use leds::playback::Control;
+use leds::protocol::{self, ErrorKind, MAX_LINE, Request};
+use leds::report::{self, Format};
+use leds::validation;
+
+fn config_json() -> serde_json::Value {
+    serde_json::to_value(DisplayConfig::default()).unwrap()
+}
+
+fn lines(input: &[u8], limit: usize) -> Vec<Result<String, protocol::Error>> {
+    let mut reader = Cursor::new(input);
+    let mut lines = Vec::new();
+    while let Some(line) = protocol::read_line(&mut reader, limit).unwrap() {
+        lines.push(line);
+    }
+    lines
+}
+
+fn apply(line: &str) -> Result<DisplayConfig, protocol::Error> {
+    match protocol::parse(line)? {
+        Request::Config(patch) => protocol::apply(&DisplayConfig::default(), &patch),
+        other => panic!("expected a config, got {:?}", other),
+    }
+}
+
+#[test]
+fn full_config_is_accepted() {
+    let mut json = config_json();
+    json["fade"] = 0.5.into();
+    let config = apply(&json.to_string()).unwrap();
+    assert_eq!(config.fade, 0.5);
+}
+
+#[test]
+fn partial_config_is_merged() {
+    let config = apply(r#"{"white":{"hue":120},"brightness":0.25}"#).unwrap();
+    let json = serde_json::to_value(&config).unwrap();
+    assert_eq!(json["white"]["hue"], 120.0);
+    assert_eq!(json["white"]["saturation"], 1.0);
+    assert_eq!(config.brightness, 0.25);
+    assert_eq!(config.fade, DisplayConfig::default().fade);
+}
+
+#[test]
+fn null_resets_to_default() {
+    let mut current = DisplayConfig::default();
+    current.key_palette = true;
+    let patch = serde_json::json!({"key_palette": null});
+    let config = protocol::apply(&current, &patch).unwrap();
+    assert!(!config.key_palette);
+}
+
+#[test]
+fn playback_command_is_accepted() {
+    let line = r#"{"playback":{"action":"seek","seconds":30}}"#;
+    match protocol::parse(line) {
+        Ok(Request::Playback(control)) => assert_eq!(control, Control::Seek { seconds: 30.0 }),
+        other => panic!("expected a playback command, got {:?}", other),
+    }
+}
+
+#[test]
+fn malformed_json_is_a_syntax_error() {
+    for line in ["not json", "{\"fade\": }", "{'fade': 0.5}", ""] {
+        let error = protocol::parse(line).unwrap_err();
+        assert_eq!(error.kind, ErrorKind::Syntax, "{}", line);
+        assert!(error.column.is_some());
+    }
+}
+
+#[test]
+fn truncated_line_is_a_syntax_error() {
+    let line = config_json().to_string();
+    let error = protocol::parse(&line[..line.len() / 2]).unwrap_err();
+    assert_eq!(error.kind, ErrorKind::Syntax);
+}
+
+#[test]
+fn removing_required_field_resets_it() {
+    let mut current = DisplayConfig::default();
+    current.fade = 0.5;
+    current.brightness = 0.25;
+    let patch = serde_json::json!({"fade": null, "brightness": null, "white": null});
+    let config = protocol::apply(&current, &patch).unwrap();
+    let defaults = DisplayConfig::default();
+    assert_eq!(config.fade, defaults.fade);
+    assert_eq!(config.brightness, defaults.brightness);
+    assert_eq!(config, defaults);
+}
+
+#[test]
+fn null_resets_nested_field() {
+    let mut current = DisplayConfig::default();
+    current.brightness = 0.25;
+    let current = protocol::apply(&current, &serde_json::json!({"white": {"hue": 120}})).unwrap();
+    let config = protocol::apply(&current, &serde_json::json!({"white": {"hue": null}})).unwrap();
+    let json = serde_json::to_value(&config).unwrap();
+    let defaults = config_json();
+    assert_eq!(json["white"]["hue"], defaults["white"]["hue"]);
+    assert_eq!(config.brightness, 0.25);
+}
+
+#[test]
+fn patch_can_switch_variant() {
+    // the default weighting is exponential
+    let config = apply(r#"{"weighting":{"type":"gaussian","sigma":2.0}}"#).unwrap();
+    assert_eq!(config.weighting, Weighting::Gaussian { sigma: 2.0 });
+    let patch = serde_json::json!({"weighting": {"sigma": 3.0}});
+    let config = protocol::apply(&config, &patch).unwrap();
+    assert_eq!(config.weighting, Weighting::Gaussian { sigma: 3.0 });
+}
+
+#[test]
+fn patch_for_another_variant_is_rejected() {
+    // sigma belongs to the gaussian weighting, not the current exponential
+    let error = apply(r#"{"weighting":{"sigma":2.0}}"#).unwrap_err();
+    assert_eq!(error.kind, ErrorKind::Invalid);
+    assert!(error.message.contains("sigma"), "{}", error.message);
+}
+
+#[test]
+fn unknown_fields_are_reported() {
+    let error = apply(r#"{"brightnes":0.5}"#).unwrap_err();
+    assert_eq!(error.kind, ErrorKind::Invalid);
+    assert_eq!(error.field.as_deref(), Some("brightnes"));
+
+    // a misspelt command would otherwise pass as an empty config change
+    let error = apply(r#"{"comand":"pause"}"#).unwrap_err();
+    assert_eq!(error.kind, ErrorKind::Invalid);
+    assert_eq!(error.field.as_deref(), Some("comand"));
+}
+
+#[test]
+fn wrong_type_names_the_field() {
+    let error = apply(r#"{"white":{"hue":"red"}}"#).unwrap_err();
+    assert_eq!(error.kind, ErrorKind::Invalid);
+    assert_eq!(error.field.as_deref(), Some("white.hue"));
+}
+
+#[test]
+fn unknown_note_name_names_the_field() {
+    for root in ["H", "Xq", "c"] {
+        let patch = format!(r#"{{"overlay":{{"root":"{}","scale":"major"}}}}"#, root);
+        let error = apply(&patch).unwrap_err();
+        assert_eq!(error.kind, ErrorKind::Invalid);
+        assert_eq!(error.field.as_deref(), Some("overlay.root"));
+    }
+    assert!(apply(r#"{"overlay":{"root":"Eb","scale":"major"}}"#).is_ok());
+}
+
+#[test]
+fn bad_playback_command_names_the_field() {
+    let error = protocol::parse(r#"{"playback":{"action":"rewind"}}"#).unwrap_err();
+    assert_eq!(error.kind, ErrorKind::Invalid);
+    assert_eq!(error.field.as_deref(), Some("playback.action"));
+}
+
+#[test]
+fn non_object_is_invalid() {
+    for line in ["[1, 2]", "42", "null"] {
+        let error = protocol::parse(line).unwrap_err();
+        assert_eq!(error.kind, ErrorKind::Invalid, "{}", line);
+    }
+}
+
+#[test]
+fn error_reply_is_json() {
+    let error = protocol::parse("{").unwrap_err();
+    let reply = serde_json::to_value(&error).unwrap();
+    assert_eq!(reply["kind"], "syntax");
+    assert!(reply.get("field").is_none());
+}
+
+#[test]
+fn lines_are_split_on_newlines() {
+    let read = lines(b"{\"a\":1}\n{\"b\":2}\nlast", MAX_LINE);
+    let read: Vec<String> = read.into_iter().map(Result::unwrap).collect();
+    assert_eq!(read, ["{\"a\":1}", "{\"b\":2}", "last"]);
+}
+
+#[test]
+fn oversized_line_is_skipped() {
+    let mut input = vec![b'x'; 100];
+    input.extend_from_slice(b"\nshort\n");
+    let read = lines(&input, 10);
+    assert_eq!(read.len(), 2);
+    assert_eq!(read[0].as_ref().unwrap_err().kind, ErrorKind::TooLong);
+    assert_eq!(read[1].as_ref().unwrap(), "short");
+}
+
+#[test]
+fn oversized_line_across_reads_is_skipped() {
+    // a small buffer makes the long line arrive in many pieces
+    let mut input = vec![b'x'; 1000];
+    input.extend_from_slice(b"\nshort\n");
+    let mut reader = std::io::BufReader::with_capacity(16, Cursor::new(input));
+    let first = protocol::read_line(&mut reader, 100).unwrap().unwrap();
+    assert_eq!(first.unwrap_err().kind, ErrorKind::TooLong);
+    let second = protocol::read_line(&mut reader, 100).unwrap().unwrap();
+    assert_eq!(second.unwrap(), "short");
+    assert!(protocol::read_line(&mut reader, 100).unwrap().is_none());
+}
+
+#[test]
+fn invalid_utf8_is_an_encoding_error() {
+    let read = lines(b"\xff\xfe\n{}\n", MAX_LINE);
+    assert_eq!(read[0].as_ref().unwrap_err().kind, ErrorKind::Encoding);
+    assert_eq!(read[1].as_ref().unwrap(), "{}");
+}
+
+#[test]
+fn command_is_parsed_with_its_id() {
+    match protocol::parse(r#"{"id":7,"command":"identify","key":49}"#) {
+        Ok(Request::Command { id, command }) => {
+            assert_eq!(id, Some(7.into()));
+            let expected = Command::Identify {
+                key: 49,
+                seconds: 3.0,
+            };
+            assert_eq!(command, expected);
+        }
+        other => panic!("expected a command, got {:?}", other),
+    }
+}
+
+#[test]
+fn unknown_command_keeps_the_id() {
+    let error = protocol::parse(r#"{"id":"abc","command":"explode"}"#).unwrap_err();
+    assert_eq!(error.kind, ErrorKind::Invalid);
+    assert_eq!(error.id, Some("abc".into()));
+}
+
+fn pipeline() -> Pipeline {
+    Pipeline::new(Source::Midi(Arc::new(Mutex::new(Keyboard::new()))))
+}
+
+#[test]
+fn commands_change_state() {
+    let mut pipeline = pipeline();
+    let config = DisplayConfig::default();
+    let reply = pipeline.command(&Command::Pause, &config).unwrap();
+    assert_eq!(reply["state"], "paused");
+    assert_eq!(pipeline.state(), State::Paused);
+    pipeline.command(&Command::Blackout, &config).unwrap();
+    assert_eq!(pipeline.state(), State::Blackout);
+    pipeline.command(&Command::Resume, &config).unwrap();
+    assert_eq!(pipeline.state(), State::Live);
+}
+
+#[test]
+fn identify_goes_back_to_previous_state() {
+    let mut pipeline = pipeline();
+    let mut config = DisplayConfig::default();
+    pipeline.command(&Command::Pause, &config).unwrap();
+    let identify = Command::Identify {
+        key: 49,
+        seconds: 0.0,
+    };
+    let reply = pipeline.command(&identify, &config).unwrap();
+    assert_eq!(reply["key"], 49);
+    pipeline.frame(&mut config, &mut Null::new()).unwrap();
+    assert_eq!(pipeline.state(), State::Paused);
+}
+
+#[test]
+fn identify_rejects_keys_off_the_strip() {
+    let mut pipeline = pipeline();
+    let config = DisplayConfig::default();
+    for key in [0, 1, 89] {
+        let identify = Command::Identify { key, seconds: 1.0 };
+        assert!(pipeline.command(&identify, &config).is_err(), "{}", key);
+    }
+    assert_eq!(pipeline.state(), State::Live);
+}
+
+#[test]
+fn state_and_snapshot_replies() {
+    let mut pipeline = pipeline();
+    let config = DisplayConfig::default();
+    let state = pipeline.command(&Command::State, &config).unwrap();
+    assert_eq!(state["state"], "live");
+    assert_eq!(state["config"]["fade"], 0.9f32 as f64);
+    let snapshot = pipeline.command(&Command::Snapshot, &config).unwrap();
+    let levels = snapshot["levels"].as_array().unwrap();
+    assert_eq!(levels.len(), pipeline.bins().len());
+}
+
+#[test]
+fn etf_packets_are_decoded() {
+    let mut input = Vec::new();
+    let command = etf::encode(&serde_json::json!({"id": 1, "command": "pause"})).unwrap();
+    etf::write_packet(&mut input, &command).unwrap();
+    etf::write_packet(&mut input, b"not a term").unwrap();
+    let patch = etf::encode(&serde_json::json!({"white": {"hue": 120}})).unwrap();
+    etf::write_packet(&mut input, &patch).unwrap();
+
+    let mut reader = Cursor::new(input);
+    let mut next = || protocol::read_request(&mut reader, Format::Etf).unwrap();
+    match next() {
+        Some(Ok(Request::Command { id, command })) => {
+            assert_eq!(id, Some(1.into()));
+            assert_eq!(command, Command::Pause);
+        }
+        other => panic!("expected a command, got {:?}", other),
+    }
+    assert_eq!(next().unwrap().unwrap_err().kind, ErrorKind::Syntax);
+    assert!(matches!(next(), Some(Ok(Request::Config(_)))));
+    assert!(next().is_none());
+}
+
+#[test]
+fn blank_json_lines_are_skipped() {
+    let mut reader = Cursor::new(b"\n  \n{\"command\":\"state\"}\n".to_vec());
+    let request = protocol::read_request(&mut reader, Format::Json).unwrap();
+    assert!(matches!(request, Some(Ok(Request::Command { .. }))));
+    assert!(
+        protocol::read_request(&mut reader, Format::Json)
+            .unwrap()
+            .is_none()
+    );
+}
+
+#[test]
+fn out_of_range_config_is_rejected() {
+    let config = DisplayConfig::default();
+    let err = protocol::apply(&config, &serde_json::json!({"fade": 1.2})).unwrap_err();
+    assert_eq!(err.kind, ErrorKind::Invalid);
+    assert_eq!(err.field.as_deref(), Some("fade"));
+    let err = protocol::apply(&config, &serde_json::json!({"decay": 0})).unwrap_err();
+    assert_eq!(err.field.as_deref(), Some("decay"));
+}
+
+#[test]
+fn out_of_range_config_is_clamped() {
+    let config = DisplayConfig::default();
+    let patch = serde_json::json!({"brightness": -0.5, "white": {"hue": -30}});
+    let updated = protocol::apply(&config, &patch).unwrap();
+    let updated = serde_json::to_value(updated).unwrap();
+    assert_eq!(updated["brightness"], 0.0);
+    assert_eq!(updated["white"]["hue"], 330.0);
+}
+
+#[test]
+fn out_of_range_command_is_rejected() {
+    let err = protocol::parse(r#"{"id":4,"command":"identify","key":0}"#).unwrap_err();
+    assert_eq!(err.kind, ErrorKind::Invalid);
+    assert_eq!(err.field.as_deref(), Some("key"));
+    assert_eq!(err.id, Some(4.into()));
+    let err = protocol::parse(r#"{"playback":{"action":"speed","factor":0}}"#).unwrap_err();
+    assert_eq!(err.field.as_deref(), Some("playback.factor"));
+}
+
+#[test]
+fn schema_lists_ranges() {
+    let schema = validation::protocol_schema();
+    let fade = &schema["config"]["properties"]["fade"];
+    assert_eq!(fade["exclusiveMaximum"], 1.0);
+    assert!(schema["command"]["oneOf"].is_array());
+    let root = &schema["config"]["$defs"]["PitchClass"];
+    assert_eq!(root["enum"].as_array().map(Vec::len), Some(12));
+}
+
+#[test]
+fn schema_describes_config_fields() {
+    let schema = validation::protocol_schema();
+    let properties = schema["config"]["properties"].as_object().unwrap();
+    for (name, property) in properties {
+        assert!(
+            property["description"].is_string(),
+            "{} has no description",
+            name
+        );
+    }
+}
+
+// a sink the test can read back, other tests may send to it at the same time
+#[derive(Clone, Default)]
+struct Captured(Arc<Mutex<Vec<u8>>>);
+
+impl std::io::Write for Captured {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn replies_go_to_the_sink() {
+    let captured = Captured::default();
+    report::set_sink(captured.clone());
+    protocol::reply(
+        Some(&"sink".into()),
+        &serde_json::json!({"state": "paused"}),
+    );
+    let output = String::from_utf8(captured.0.lock().unwrap().clone()).unwrap();
+    assert!(
+        output
+            .lines()
+            .any(|line| line == r#"{"id":"sink","reply":{"state":"paused"}}"#),
+        "{}",
+        output
+    );
+}
+
+#[test]
+fn adjustments_go_to_the_logger() {
+    let logged = Arc::new(Mutex::new(Vec::new()));
+    let logger = Arc::clone(&logged);
+    report::set_logger(move |message| logger.lock().unwrap().push(message.to_string()));
+    let patch = serde_json::json!({"sensitivity": 150});
+    protocol::apply(&DisplayConfig::default(), &patch).unwrap();
+    let logged = logged.lock().unwrap();
+    assert!(
+        logged
+            .iter()
+            .any(|message| message == "sensitivity is out of range, using 100 instead of 150"),
+        "{:?}",
+        logged
+    );
+}